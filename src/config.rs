@@ -0,0 +1,93 @@
+use crate::{
+    highlight::{HeaderHighlight, HighlightConfig, RowHighlight},
+    hook::Hook,
+    keymap::Keymap,
+    query::Query,
+};
+
+use anyhow::Context;
+use std::fs;
+
+// -------------------------------------------------------------------------------------------------
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub(crate) struct Config {
+    pub(crate) highlights: HighlightConfig,
+
+    // Styles for whole rows of the message list.
+    pub(crate) row_highlights: Vec<RowHighlight>,
+
+    // Highlights for the header values shown in the message list.
+    pub(crate) header_highlights: Vec<HeaderHighlight>,
+
+    pub(crate) keys: Keymap,
+
+    // Seconds a message must be open before it is marked read, or zero to mark it read as soon as
+    // it is selected.  Scrolling the message body marks it read immediately.
+    pub(crate) mark_read_delay: f64,
+
+    // Whether to summarise the pending changes and ask before quitting with them.
+    pub(crate) confirm_quit: bool,
+
+    // Commands to run for newly delivered messages.
+    pub(crate) hooks: Vec<Hook>,
+
+    // Extra counts of messages matching queries for `smbox status`.
+    pub(crate) status_counts: Vec<StatusCount>,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub(crate) struct StatusCount {
+    pub(crate) name: String,
+    pub(crate) query: Query,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            highlights: HighlightConfig::default(),
+            row_highlights: Vec::default(),
+            header_highlights: Vec::default(),
+            keys: Keymap::default(),
+            mark_read_delay: 0.0,
+            confirm_quit: false,
+            hooks: Vec::default(),
+            status_counts: Vec::default(),
+        }
+    }
+}
+
+impl Config {
+    pub(crate) fn mark_read_delay(&self) -> std::time::Duration {
+        std::time::Duration::try_from_secs_f64(self.mark_read_delay).unwrap_or_default()
+    }
+}
+
+// -------------------------------------------------------------------------------------------------
+
+pub(crate) fn load() -> anyhow::Result<Config> {
+    let base_dirs =
+        directories::BaseDirs::new().context("Failed to determine config file path.")?;
+
+    let mut config_file_path = base_dirs.config_dir().to_owned();
+    config_file_path.push("smbox.ron");
+
+    Ok(if fs::exists(&config_file_path)? {
+        load_from(&config_file_path)?
+    } else {
+        Config::default()
+    })
+}
+
+pub(crate) fn load_from(config_file_path: &std::path::Path) -> anyhow::Result<Config> {
+    ron::from_str(&fs::read_to_string(config_file_path).with_context(|| {
+        format!(
+            "Failed to read from config file at {}.",
+            config_file_path.display()
+        )
+    })?)
+    .with_context(|| format!("Invalid config file at {}.", config_file_path.display()))
+}
+
+// -------------------------------------------------------------------------------------------------
@@ -1,98 +1,1219 @@
-type Colour256 = u8;
+use crate::{mbox::Message, query::Query};
 
-#[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+use std::convert::TryFrom;
+
+#[derive(Debug, Default, serde::Serialize)]
+#[serde(transparent)]
 pub(crate) struct HighlightConfig {
-    #[serde(rename = "highlights")]
     ctx_matches: Vec<HighlightContext>,
+
+    // Every context's `enter` pattern, to find which may be entered with one pass over a line.
+    #[serde(skip)]
+    enter_set: regex::RegexSet,
+}
+
+// The contexts are checked against every line, so they're prepared for that as they're loaded.
+impl<'de> serde::Deserialize<'de> for HighlightConfig {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut ctx_matches = Vec::<HighlightContext>::deserialize(deserializer)?;
+
+        let mut enter_patterns = Vec::new();
+        prepare_contexts(&mut ctx_matches, "", &mut enter_patterns)
+            .map_err(serde::de::Error::custom)?;
+        let enter_set = regex::RegexSet::new(enter_patterns).map_err(serde::de::Error::custom)?;
+
+        Ok(HighlightConfig {
+            ctx_matches,
+            enter_set,
+        })
+    }
+}
+
+fn prepare_contexts(
+    contexts: &mut [HighlightContext],
+    label_prefix: &str,
+    enter_patterns: &mut Vec<String>,
+) -> Result<(), regex::Error> {
+    for (idx, ctx) in contexts.iter_mut().enumerate() {
+        ctx.label = format!("{label_prefix}{}", idx + 1);
+        if let Some(enter_re) = &ctx.ctx_enter_re {
+            ctx.enter_set_idx = enter_patterns.len();
+            enter_patterns.push(enter_re.as_str().to_owned());
+        }
+        ctx.match_set = regex::RegexSet::new(ctx.matches.iter().map(|rule| rule.re.as_str()))?;
+
+        prepare_contexts(
+            &mut ctx.contexts,
+            &format!("{}.", ctx.label),
+            enter_patterns,
+        )?;
+    }
+    Ok(())
 }
 
 impl<'h> HighlightConfig {
-    pub(crate) fn highlighter(&'h self) -> Highlighter<'h> {
+    // A highlighter for the body of `msg`, using only the contexts which are scoped to it.
+    pub(crate) fn highlighter(&'h self, msg: &Message) -> Highlighter<'h> {
+        let mut contexts = Vec::new();
+        scoped_contexts(&self.ctx_matches, msg, &mut contexts);
+
         Highlighter {
-            config: self,
-            cur_ctx: None,
+            enter_set: &self.enter_set,
+            global_active: contexts
+                .iter()
+                .map(|ctx| ctx.ctx_enter_re.is_none())
+                .collect(),
+            contexts,
+            ctx_stack: Vec::new(),
+            trace: None,
         }
     }
 }
 
+fn scoped_contexts<'h>(
+    contexts: &'h [HighlightContext],
+    msg: &Message,
+    scoped: &mut Vec<&'h HighlightContext>,
+) {
+    for ctx in contexts {
+        if ctx.when.matches(msg) {
+            // A context which is only a group has nothing to do itself.
+            if ctx.ctx_enter_re.is_some() || !ctx.matches.is_empty() {
+                scoped.push(ctx);
+            }
+            scoped_contexts(&ctx.contexts, msg, scoped);
+        }
+    }
+}
+
+// A context without an `enter` pattern is global: it's active from the start of the body until its
+// `exit` pattern matches, if it has one, regardless of any other context.
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 struct HighlightContext {
-    #[serde(with = "serde_regex", rename = "enter")]
-    ctx_enter_re: regex::Regex,
+    #[serde(
+        with = "plain_opt_regex",
+        default,
+        skip_serializing_if = "Option::is_none",
+        rename = "enter"
+    )]
+    ctx_enter_re: Option<regex::Regex>,
 
     #[serde(with = "serde_regex", default, rename = "exit")]
     ctx_exit_re: Option<regex::Regex>,
 
+    #[serde(default)]
     matches: Vec<HighlightMatch>,
+
+    // Only use this context, and those grouped within it, for messages which match this query.
+    #[serde(default)]
+    when: Query,
+
+    #[serde(default)]
+    contexts: Vec<HighlightContext>,
+
+    // Whether the matches of the context this one is nested within also apply.
+    #[serde(default)]
+    inherit: bool,
+
+    // Whether the lines which enter and exit this context are highlighted by it.
+    #[serde(default)]
+    highlight_bounds: bool,
+
+    // The position of the `enter` pattern in the config's `enter_set`, if there is one.
+    #[serde(skip)]
+    enter_set_idx: usize,
+
+    // Every match pattern, to find which may match with one pass over a line.
+    #[serde(skip)]
+    match_set: regex::RegexSet,
+
+    // The position of this context in the config, e.g., '2' or '2.1' for the first in a group, for
+    // tracing.
+    #[serde(skip)]
+    label: String,
+}
+
+impl HighlightContext {
+    // Whether this context is entered, given the matches for a line from the config's `enter_set`.
+    fn is_entered_by(&self, entered: &regex::SetMatches) -> bool {
+        self.ctx_enter_re.is_some() && entered.matched(self.enter_set_idx)
+    }
+
+    fn is_exited_by(&self, line: &str) -> bool {
+        self.ctx_exit_re
+            .as_ref()
+            .is_some_and(|re| re.is_match(line))
+    }
+
+    fn find_highlights(
+        &self,
+        ctx_idx: usize,
+        depth: usize,
+        line: &str,
+        highlights: &mut Vec<Highlight>,
+        line_styles: &mut Vec<(Rank, HighlightStyle)>,
+    ) {
+        let candidates = self.match_set.matches(line);
+        if candidates.matched_any() {
+            find_match_highlights(
+                &self.matches,
+                candidates.into_iter(),
+                ctx_idx,
+                depth,
+                line,
+                highlights,
+                line_styles,
+            );
+        }
+    }
+}
+
+// Find every match in a line for the rules in `matches` at `rule_idcs`, adding them to
+// `highlights`, or to `line_styles` for whole line matches.
+fn find_match_highlights(
+    matches: &[HighlightMatch],
+    rule_idcs: impl Iterator<Item = usize>,
+    ctx_idx: usize,
+    depth: usize,
+    line: &str,
+    highlights: &mut Vec<Highlight>,
+    line_styles: &mut Vec<(Rank, HighlightStyle)>,
+) {
+    for rule_idx in rule_idcs {
+        let rule = &matches[rule_idx];
+        let rank = |group_idx| Rank {
+            priority: rule.priority,
+            depth,
+            ctx_idx,
+            rule_idx,
+            group_idx,
+        };
+
+        if rule.whole_line {
+            if rule.re.is_match(line) {
+                line_styles.push((rank(0), rule.style_with_fg(rule.colour)));
+            }
+            continue;
+        }
+
+        let mut push_highlight = |mtch: regex::Match, group_idx, fg| {
+            highlights.push(Highlight {
+                begin: mtch.start(),
+                end: mtch.end(),
+                style: rule.style_with_fg(fg),
+                rank: rank(group_idx),
+            })
+        };
+
+        for caps in rule.re.captures_iter(line) {
+            if rule.group_colours.is_empty() || caps.len() == 1 {
+                // Just the first capture, or the whole match if there are none, even when there
+                // are group colours.
+                let group_idx = if caps.len() == 1 { 0 } else { 1 };
+                let mtch = caps
+                    .get(group_idx)
+                    .expect("BUG! `caps` is guaranteed to have at least one match.");
+                push_highlight(mtch, group_idx, rule.colour);
+            } else {
+                for (group_idx, mtch) in caps.iter().enumerate().skip(1) {
+                    if let Some(mtch) = mtch {
+                        let group_colour = rule
+                            .group_colours
+                            .get(group_idx - 1)
+                            .copied()
+                            .unwrap_or(rule.colour);
+                        push_highlight(mtch, group_idx, group_colour);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Optional regexes which are written as plain strings in the config, rather than as `Some(..)`.
+mod plain_opt_regex {
+    pub(super) fn deserialize<'de, D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<regex::Regex>, D::Error> {
+        serde_regex::deserialize(deserializer).map(Some)
+    }
+
+    pub(super) fn serialize<S: serde::Serializer>(
+        re: &Option<regex::Regex>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match re {
+            Some(re) => serde_regex::serialize(re, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
 }
 
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 struct HighlightMatch {
     #[serde(with = "serde_regex", rename = "match")]
     re: regex::Regex,
-    colour: Colour256,
+
+    // The foreground colour, as a shorthand for setting it in the style.
+    #[serde(default)]
+    colour: Colour,
+
+    #[serde(default)]
+    style: HighlightStyle,
+
+    // When set, every capture group is highlighted, each with the foreground colour at its
+    // position here, or `colour` if there aren't enough.  Without any groups the whole match is
+    // highlighted with `colour`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    group_colours: Vec<Colour>,
+
+    // Where highlights overlap the one with the highest priority is shown.
+    #[serde(default)]
+    priority: i32,
+
+    // Style the whole line when it matches, underneath any other highlights.
+    #[serde(default)]
+    whole_line: bool,
 }
 
-#[derive(Default)]
+impl HighlightMatch {
+    fn style_with_fg(&self, fg: Colour) -> HighlightStyle {
+        self.style.with_fg(fg)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub(crate) struct HighlightStyle {
+    pub(crate) fg: Colour,
+    pub(crate) bg: Colour,
+    pub(crate) bold: bool,
+    pub(crate) italic: bool,
+    pub(crate) underline: bool,
+    pub(crate) reverse: bool,
+}
+
+impl HighlightStyle {
+    // This style with its foreground colour replaced by `fg`, if it's set.
+    fn with_fg(self, fg: Colour) -> HighlightStyle {
+        match fg {
+            Colour::Unset => self,
+            fg => HighlightStyle { fg, ..self },
+        }
+    }
+
+    // The ANSI SGR escape sequence which sets this style in a terminal, on top of whatever style is
+    // already set.  An empty sequence would reset everything instead, so none is used at all when
+    // there is nothing to set.
+    fn ansi_escape(&self) -> String {
+        let mut codes = Vec::new();
+        if let Some(fg) = self.fg.ansi_code() {
+            codes.push(format!("38;{fg}"));
+        }
+        if let Some(bg) = self.bg.ansi_code() {
+            codes.push(format!("48;{bg}"));
+        }
+        for (enabled, code) in [
+            (self.bold, "1"),
+            (self.italic, "3"),
+            (self.underline, "4"),
+            (self.reverse, "7"),
+        ] {
+            if enabled {
+                codes.push(code.to_owned());
+            }
+        }
+
+        if codes.is_empty() {
+            String::new()
+        } else {
+            format!("\x1b[{}m", codes.join(";"))
+        }
+    }
+}
+
+// A colour is a 256 colour index, one of the standard colour names, e.g., "red" or "light_green",
+// which are the first 16 indices, or "#rrggbb" for true colour.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum Colour {
+    #[default]
+    Unset,
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+const COLOUR_NAMES: &[&str] = &[
+    "black",
+    "red",
+    "green",
+    "yellow",
+    "blue",
+    "magenta",
+    "cyan",
+    "gray",
+    "dark_gray",
+    "light_red",
+    "light_green",
+    "light_yellow",
+    "light_blue",
+    "light_magenta",
+    "light_cyan",
+    "white",
+];
+
+impl Colour {
+    fn ansi_code(&self) -> Option<String> {
+        match self {
+            Colour::Unset => None,
+            Colour::Indexed(idx) => Some(format!("5;{idx}")),
+            Colour::Rgb(r, g, b) => Some(format!("2;{r};{g};{b}")),
+        }
+    }
+}
+
+impl std::str::FromStr for Colour {
+    type Err = String;
+
+    fn from_str(colour_str: &str) -> Result<Self, Self::Err> {
+        let name = colour_str
+            .to_ascii_lowercase()
+            .replace(['-', ' '], "_")
+            .replace("grey", "gray");
+        if let Some(hex) = name.strip_prefix('#') {
+            let component = |idx: usize| {
+                hex.get(idx..idx + 2)
+                    .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+            };
+            match (hex.len(), component(0), component(2), component(4)) {
+                (6, Some(r), Some(g), Some(b)) => Ok(Colour::Rgb(r, g, b)),
+                _ => Err(format!(
+                    "Invalid colour '{colour_str}', expecting '#rrggbb'."
+                )),
+            }
+        } else if name == "default" {
+            Ok(Colour::Unset)
+        } else {
+            COLOUR_NAMES
+                .iter()
+                .position(|known| *known == name)
+                .map(|idx| Colour::Indexed(idx as u8))
+                .ok_or_else(|| {
+                    format!(
+                        "Unknown colour '{colour_str}', expecting a colour index, '#rrggbb' or one of: {}.",
+                        COLOUR_NAMES.join(", ")
+                    )
+                })
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Colour {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ColourVisitor;
+
+        impl serde::de::Visitor<'_> for ColourVisitor {
+            type Value = Colour;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a colour index, name or '#rrggbb'")
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, idx: u64) -> Result<Colour, E> {
+                u8::try_from(idx)
+                    .map(Colour::Indexed)
+                    .map_err(|_| E::custom(format!("Colour index {idx} is not from 0 to 255.")))
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, idx: i64) -> Result<Colour, E> {
+                u8::try_from(idx)
+                    .map(Colour::Indexed)
+                    .map_err(|_| E::custom(format!("Colour index {idx} is not from 0 to 255.")))
+            }
+
+            fn visit_str<E: serde::de::Error>(self, colour_str: &str) -> Result<Colour, E> {
+                colour_str.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(ColourVisitor)
+    }
+}
+
+impl serde::Serialize for Colour {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Colour::Unset => serializer.serialize_str("default"),
+            Colour::Indexed(idx) => serializer.serialize_u8(*idx),
+            Colour::Rgb(r, g, b) => serializer.serialize_str(&format!("#{r:02x}{g:02x}{b:02x}")),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
 pub(crate) struct Highlight {
     pub(crate) begin: usize,
     pub(crate) end: usize,
-    pub(crate) colour: Colour256,
+    pub(crate) style: HighlightStyle,
+    rank: Rank,
+}
+
+// The highlights for a line, and the style for the whole line if it has one.
+#[derive(Default)]
+pub(crate) struct LineHighlights {
+    pub(crate) style: Option<HighlightStyle>,
+    pub(crate) spans: Vec<Highlight>,
+
+    // Which rule the line style is from, for tracing.
+    style_rank: Rank,
+}
+
+impl LineHighlights {
+    fn new(highlights: Vec<Highlight>, line_styles: Vec<(Rank, HighlightStyle)>) -> Self {
+        let line_style = line_styles.into_iter().max_by_key(|(rank, _)| *rank);
+        LineHighlights {
+            style: line_style.map(|(_, style)| style),
+            spans: merge_highlights(highlights),
+            style_rank: line_style.map(|(rank, _)| rank).unwrap_or_default(),
+        }
+    }
+}
+
+// Overlapping highlights are resolved by rank: the highest priority wins, then the rule in the more
+// deeply nested context, then in the later context, then the later rule, then the later capture
+// group.  Global contexts have depth 0.
+#[derive(Clone, Copy, Default, Eq, Ord, PartialEq, PartialOrd)]
+struct Rank {
+    priority: i32,
+    depth: usize,
+    ctx_idx: usize,
+    rule_idx: usize,
+    group_idx: usize,
+}
+
+// Rules for the message list.  Row rules style the whole row of each message which matches a query,
+// and header rules highlight the header values shown for each message, e.g., the subject.
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub(crate) struct RowHighlight {
+    when: Query,
+
+    #[serde(default)]
+    colour: Colour,
+
+    #[serde(default)]
+    style: HighlightStyle,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub(crate) struct HeaderHighlight {
+    // The header name, compared case insensitively.
+    header: String,
+
+    #[serde(default)]
+    when: Query,
+
+    matches: Vec<HighlightMatch>,
+}
+
+// The style for the row of a message in the list.  The last matching rule is used.
+pub(crate) fn row_style(rules: &[RowHighlight], msg: &Message) -> Option<HighlightStyle> {
+    rules
+        .iter()
+        .rev()
+        .find(|rule| rule.when.matches(msg))
+        .map(|rule| rule.style.with_fg(rule.colour))
+}
+
+// The highlights for the value of a header of a message.  Whole line matches style the entire value.
+pub(crate) fn header_highlights(
+    rules: &[HeaderHighlight],
+    msg: &Message,
+    header: &str,
+    value: &str,
+) -> LineHighlights {
+    let mut highlights = Vec::default();
+    let mut line_styles = Vec::default();
+    for (rule_idx, rule) in rules.iter().enumerate() {
+        if rule.header.eq_ignore_ascii_case(header) && rule.when.matches(msg) {
+            find_match_highlights(
+                &rule.matches,
+                0..rule.matches.len(),
+                rule_idx,
+                0,
+                value,
+                &mut highlights,
+                &mut line_styles,
+            );
+        }
+    }
+    LineHighlights::new(highlights, line_styles)
 }
 
 pub(crate) struct Highlighter<'h> {
-    config: &'h HighlightConfig,
-    cur_ctx: Option<usize>,
+    contexts: Vec<&'h HighlightContext>,
+    enter_set: &'h regex::RegexSet,
+
+    // The contexts which have been entered and not yet exited, innermost last.
+    ctx_stack: Vec<usize>,
+
+    // Whether each global context is still active, i.e., hasn't been exited.
+    global_active: Vec<bool>,
+
+    // When tracing, a description of each context entered or exited and each rule matched.
+    trace: Option<Vec<String>>,
 }
 
 impl<'h> Highlighter<'h> {
-    pub(crate) fn next_highlights(&mut self, next_line: &str) -> Vec<Highlight> {
-        // First check if we're matching a new context.
-        if let Some(new_ctx_idx) =
-            self.config
-                .ctx_matches
-                .iter()
-                .enumerate()
-                .find_map(|(idx, ctx_matcher)| {
-                    ctx_matcher.ctx_enter_re.is_match(next_line).then_some(idx)
-                })
-        {
-            // Set the new context and return empty highlights.
-            self.cur_ctx = Some(new_ctx_idx);
-            return Vec::default();
+    pub(crate) fn with_trace(self) -> Self {
+        Highlighter {
+            trace: Some(Vec::new()),
+            ..self
         }
+    }
+
+    // The trace for the lines highlighted since it was last taken.
+    pub(crate) fn take_trace(&mut self) -> Vec<String> {
+        self.trace.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    fn trace(&mut self, event: impl FnOnce() -> String) {
+        if let Some(trace) = &mut self.trace {
+            trace.push(event());
+        }
+    }
+
+    // Exit the contexts on the stack after the first `stack_len`, innermost first.
+    fn exit_contexts(&mut self, stack_len: usize) {
+        while self.ctx_stack.len() > stack_len {
+            if let Some(ctx_idx) = self.ctx_stack.pop() {
+                let ctx_matcher = self.contexts[ctx_idx];
+                self.trace(|| format!("exited context {}", ctx_matcher.label));
+            }
+        }
+    }
 
-        // If we are matching a specific context then use it.
+    pub(crate) fn next_highlights(&mut self, next_line: &str) -> LineHighlights {
         let mut highlights = Vec::default();
-        if let Some(cur_ctx_idx) = self.cur_ctx {
-            let ctx_matcher = &self.config.ctx_matches[cur_ctx_idx];
+        let mut line_styles = Vec::default();
 
-            // Firstly check if we're matching this context's exit pattern.
-            if ctx_matcher
-                .ctx_exit_re
-                .iter()
-                .any(|exit_re| exit_re.is_match(next_line))
-            {
-                // Set the current context to none.
-                self.cur_ctx = None;
-            } else {
-                // Find any matches for this context.
-                for HighlightMatch { re, colour } in &ctx_matcher.matches {
-                    if let Some(caps) = re.captures(next_line) {
-                        let mtch = caps
-                            .get(if caps.len() == 1 { 0 } else { 1 })
-                            .expect("BUG! `caps` is guaranteed to have at least one match.");
-
-                        highlights.push(Highlight {
-                            begin: mtch.start(),
-                            end: mtch.end(),
-                            colour: *colour,
-                        });
+        // Global contexts apply to every line until they're exited.
+        for (ctx_idx, ctx_matcher) in self.contexts.iter().enumerate() {
+            if self.global_active[ctx_idx] {
+                let exited = ctx_matcher.is_exited_by(next_line);
+                if exited {
+                    self.global_active[ctx_idx] = false;
+                    if let Some(trace) = &mut self.trace {
+                        trace.push(format!("exited context {}", ctx_matcher.label));
                     }
                 }
+                if !exited || ctx_matcher.highlight_bounds {
+                    ctx_matcher.find_highlights(
+                        ctx_idx,
+                        0,
+                        next_line,
+                        &mut highlights,
+                        &mut line_styles,
+                    );
+                }
+            }
+        }
+
+        // Then check if we're entering a new context, which is nested within the current one.
+        // Entering a context which is already on the stack returns to it instead, exiting any
+        // nested within it.
+        let entered = self.enter_set.matches(next_line);
+        let new_ctx_idx = if entered.matched_any() {
+            self.contexts
+                .iter()
+                .position(|ctx_matcher| ctx_matcher.is_entered_by(&entered))
+        } else {
+            None
+        };
+        if let Some(new_ctx_idx) = new_ctx_idx {
+            if let Some(stack_idx) = self.ctx_stack.iter().position(|&idx| idx == new_ctx_idx) {
+                self.exit_contexts(stack_idx);
+            }
+            self.ctx_stack.push(new_ctx_idx);
+            let ctx_matcher = self.contexts[new_ctx_idx];
+            self.trace(|| format!("entered context {}", ctx_matcher.label));
+
+            if self.contexts[new_ctx_idx].highlight_bounds {
+                self.find_stack_highlights(next_line, &mut highlights, &mut line_styles);
+            }
+        } else if let Some(stack_idx) = self
+            .ctx_stack
+            .iter()
+            .rposition(|&idx| self.contexts[idx].is_exited_by(next_line))
+        {
+            // Only the innermost context which matches is exited, along with every context nested
+            // within it, so contexts sharing an exit pattern are exited one line at a time.
+            if self.contexts[self.ctx_stack[stack_idx]].highlight_bounds {
+                self.exit_contexts(stack_idx + 1);
+                self.find_stack_highlights(next_line, &mut highlights, &mut line_styles);
             }
+            self.exit_contexts(stack_idx);
+        } else {
+            self.find_stack_highlights(next_line, &mut highlights, &mut line_styles);
+        }
+
+        let line_highlights = LineHighlights::new(highlights, line_styles);
+        if self.trace.is_some() {
+            self.trace_matches(next_line, &line_highlights);
         }
+        line_highlights
+    }
+
+    fn trace_matches(&mut self, line: &str, line_highlights: &LineHighlights) {
+        let describe_rule = |rank: &Rank| {
+            let ctx_matcher = self.contexts[rank.ctx_idx];
+            let rule = &ctx_matcher.matches[rank.rule_idx];
+            let mut desc = format!(
+                "context {} match {} /{}/",
+                ctx_matcher.label,
+                rank.rule_idx + 1,
+                rule.re.as_str()
+            );
+            if rank.group_idx > 0 {
+                desc.push_str(&format!(" group {}", rank.group_idx));
+            }
+            desc
+        };
+
+        let mut events = Vec::new();
+        if line_highlights.style.is_some() {
+            events.push(format!(
+                "whole line: {}",
+                describe_rule(&line_highlights.style_rank)
+            ));
+        }
+        for highlight in &line_highlights.spans {
+            events.push(format!(
+                "{}..{} {:?}: {}",
+                highlight.begin,
+                highlight.end,
+                &line[highlight.begin..highlight.end],
+                describe_rule(&highlight.rank)
+            ));
+        }
+        if let Some(trace) = &mut self.trace {
+            trace.append(&mut events);
+        }
+    }
+
+    // Use the innermost context on the stack, and those it inherits from.
+    fn find_stack_highlights(
+        &self,
+        line: &str,
+        highlights: &mut Vec<Highlight>,
+        line_styles: &mut Vec<(Rank, HighlightStyle)>,
+    ) {
+        for (stack_idx, &ctx_idx) in self.ctx_stack.iter().enumerate().rev() {
+            let ctx_matcher = &self.contexts[ctx_idx];
+            ctx_matcher.find_highlights(ctx_idx, stack_idx + 1, line, highlights, line_styles);
+            if !ctx_matcher.inherit {
+                break;
+            }
+        }
+    }
+}
+
+// Flatten possibly overlapping highlights, in any order, into highlights which are in order and
+// don't overlap, so they can simply be rendered one after the other.  Where they overlap the
+// highest ranked highlight is used.
+fn merge_highlights(highlights: Vec<Highlight>) -> Vec<Highlight> {
+    // Split the line at every highlight boundary and find the top highlight for each piece.
+    let mut bounds = highlights
+        .iter()
+        .flat_map(|highlight| [highlight.begin, highlight.end])
+        .collect::<Vec<_>>();
+    bounds.sort_unstable();
+    bounds.dedup();
+
+    let mut merged: Vec<Highlight> = Vec::new();
+    for piece in bounds.windows(2) {
+        let (begin, end) = (piece[0], piece[1]);
+        let Some(top) = highlights
+            .iter()
+            .filter(|highlight| highlight.begin <= begin && end <= highlight.end)
+            .max_by_key(|highlight| highlight.rank)
+        else {
+            continue;
+        };
+
+        // Join pieces of the same highlight back together.
+        match merged.last_mut() {
+            Some(last) if last.end == begin && last.rank == top.rank => last.end = end,
+            _ => merged.push(Highlight {
+                begin,
+                end,
+                ..top.clone()
+            }),
+        }
+    }
 
+    merged
+}
+
+// Render a line with its highlights using ANSI terminal escapes, for printing to a terminal outside
+// of the TUI.
+pub(crate) fn ansi_line(line: &str, highlights: &LineHighlights) -> String {
+    // The line style is set first, so each highlight is shown over the top of it as in the TUI, and
+    // then restored after each highlight.
+    let line_escape = highlights
+        .style
+        .map(|style| style.ansi_escape())
+        .unwrap_or_default();
+
+    let mut ansi = line_escape.clone();
+    let mut pos = 0;
+    for Highlight {
+        begin, end, style, ..
+    } in &highlights.spans
+    {
+        ansi.push_str(&line[pos..*begin]);
+        ansi.push_str(&format!(
+            "{}{}\x1b[0m{line_escape}",
+            style.ansi_escape(),
+            &line[*begin..*end]
+        ));
+        pos = *end;
+    }
+    ansi.push_str(&line[pos..]);
+    if !line_escape.is_empty() {
+        ansi.push_str("\x1b[0m");
+    }
+    ansi
+}
+
+// -------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn match_rule(rule_str: &str) -> HighlightMatch {
+        ron::from_str(rule_str)
+            .unwrap_or_else(|err| panic!("Failed to parse '{}': {}", rule_str, err))
+    }
+
+    // The (begin, end, style) of each highlight found by a single rule.
+    fn rule_highlights(rule_str: &str, line: &str) -> Vec<(usize, usize, HighlightStyle)> {
+        let mut highlights = Vec::new();
+        find_match_highlights(
+            &[match_rule(rule_str)],
+            0..1,
+            0,
+            0,
+            line,
+            &mut highlights,
+            &mut Vec::new(),
+        );
         highlights
+            .into_iter()
+            .map(|highlight| (highlight.begin, highlight.end, highlight.style))
+            .collect()
+    }
+
+    fn fg(idx: u8) -> HighlightStyle {
+        HighlightStyle::default().with_fg(Colour::Indexed(idx))
+    }
+
+    // A highlight coloured by its rule index, so the winner of any overlap is easy to see.
+    fn highlight(
+        begin: usize,
+        end: usize,
+        priority: i32,
+        depth: usize,
+        rule_idx: usize,
+    ) -> Highlight {
+        Highlight {
+            begin,
+            end,
+            style: fg(rule_idx as u8),
+            rank: Rank {
+                priority,
+                depth,
+                rule_idx,
+                ..Rank::default()
+            },
+        }
+    }
+
+    fn merged(highlights: Vec<Highlight>) -> Vec<(usize, usize, HighlightStyle)> {
+        merge_highlights(highlights)
+            .into_iter()
+            .map(|highlight| (highlight.begin, highlight.end, highlight.style))
+            .collect()
+    }
+
+    // Highlight each line in turn with the config, marking each highlight in the line with its
+    // colour index, e.g., 'a [b:1] c'.
+    fn highlight_lines(config_str: &str, lines: &[&str]) -> Vec<String> {
+        let config: HighlightConfig = ron::from_str(config_str)
+            .unwrap_or_else(|err| panic!("Failed to parse '{}': {}", config_str, err));
+        let msg = Message::from_body(lines.iter().map(|line| line.to_string()).collect());
+        let mut highlighter = config.highlighter(&msg);
+
+        lines
+            .iter()
+            .map(|line| {
+                let line_highlights = highlighter.next_highlights(line);
+                let mut marked = String::new();
+                let mut pos = 0;
+                for highlight in &line_highlights.spans {
+                    let colour_idx = match highlight.style.fg {
+                        Colour::Indexed(idx) => idx,
+                        _ => 0,
+                    };
+                    marked.push_str(&line[pos..highlight.begin]);
+                    marked.push_str(&format!(
+                        "[{}:{}]",
+                        &line[highlight.begin..highlight.end],
+                        colour_idx
+                    ));
+                    pos = highlight.end;
+                }
+                marked.push_str(&line[pos..]);
+                marked
+            })
+            .collect()
+    }
+
+    #[test]
+    fn group_colours() {
+        let rule = r#"(match: "(\\w+): invalid user (\\w+)", colour: 1, group_colours: [2])"#;
+        assert_eq!(
+            rule_highlights(rule, "sshd: invalid user admin"),
+            vec![(0, 4, fg(2)), (19, 24, fg(1))]
+        );
+
+        // Without any groups to colour the whole match uses `colour`.
+        let rule = r#"(match: "FAILED", colour: 1, group_colours: [2])"#;
+        assert_eq!(
+            rule_highlights(rule, "backup: FAILED, FAILED"),
+            vec![(8, 14, fg(1)), (16, 22, fg(1))]
+        );
+    }
+
+    #[test]
+    fn overlapping_highlights() {
+        // The later rule wins where they overlap.
+        assert_eq!(
+            merged(vec![highlight(0, 10, 0, 0, 1), highlight(5, 15, 0, 0, 2)]),
+            vec![(0, 5, fg(1)), (5, 15, fg(2))]
+        );
+
+        // Unless the earlier one has a higher priority.
+        assert_eq!(
+            merged(vec![highlight(0, 10, 1, 0, 1), highlight(5, 15, 0, 0, 2)]),
+            vec![(0, 10, fg(1)), (10, 15, fg(2))]
+        );
+
+        // Gaps between highlights are left alone.
+        assert_eq!(
+            merged(vec![highlight(2, 4, 0, 0, 1), highlight(6, 8, 0, 0, 2)]),
+            vec![(2, 4, fg(1)), (6, 8, fg(2))]
+        );
+    }
+
+    #[test]
+    fn nested_highlights() {
+        // A higher ranked highlight inside another splits it in two.
+        assert_eq!(
+            merged(vec![highlight(0, 20, 0, 0, 1), highlight(5, 10, 0, 0, 2)]),
+            vec![(0, 5, fg(1)), (5, 10, fg(2)), (10, 20, fg(1))]
+        );
+
+        // A lower ranked one is hidden, and the pieces of the outer highlight either side of its
+        // bounds are joined back together.
+        assert_eq!(
+            merged(vec![highlight(0, 20, 0, 0, 2), highlight(5, 10, 0, 0, 1)]),
+            vec![(0, 20, fg(2))]
+        );
+        assert_eq!(
+            merged(vec![
+                highlight(0, 20, 1, 0, 1),
+                highlight(5, 10, 0, 0, 2),
+                highlight(8, 15, 0, 0, 3),
+            ]),
+            vec![(0, 20, fg(1))]
+        );
+    }
+
+    #[test]
+    fn unordered_highlights() {
+        assert_eq!(
+            merged(vec![
+                highlight(12, 16, 0, 0, 3),
+                highlight(0, 4, 0, 0, 1),
+                highlight(2, 8, 0, 0, 2),
+            ]),
+            vec![(0, 2, fg(1)), (2, 8, fg(2)), (12, 16, fg(3))]
+        );
+    }
+
+    #[test]
+    fn equal_ranges() {
+        // Priority first...
+        assert_eq!(
+            merged(vec![highlight(0, 5, 1, 0, 1), highlight(0, 5, 0, 2, 2)]),
+            vec![(0, 5, fg(1))]
+        );
+
+        // ...then the more deeply nested context...
+        assert_eq!(
+            merged(vec![highlight(0, 5, 0, 2, 1), highlight(0, 5, 0, 1, 2)]),
+            vec![(0, 5, fg(1))]
+        );
+
+        // ...then the later rule.
+        assert_eq!(
+            merged(vec![highlight(0, 5, 0, 1, 2), highlight(0, 5, 0, 1, 1)]),
+            vec![(0, 5, fg(2))]
+        );
+    }
+
+    #[test]
+    fn multi_byte_text() {
+        // Highlights are in bytes and every boundary must fall between characters.
+        let line = "ünïcode: ✓ passed, ✗ failed";
+        let rules = [
+            match_rule(r#"(match: "[✓✗]", colour: 1)"#),
+            match_rule(r#"(match: "✓ passed", colour: 2)"#),
+            match_rule(r#"(match: "ï\\w+", colour: 3)"#),
+        ];
+        let mut highlights = Vec::new();
+        find_match_highlights(
+            &rules,
+            0..rules.len(),
+            0,
+            0,
+            line,
+            &mut highlights,
+            &mut Vec::new(),
+        );
+
+        let merged = merged(highlights);
+        assert_eq!(
+            merged,
+            vec![(3, 9, fg(3)), (11, 21, fg(2)), (23, 26, fg(1))]
+        );
+        assert_eq!(
+            merged
+                .iter()
+                .map(|(begin, end, _)| &line[*begin..*end])
+                .collect::<Vec<_>>(),
+            vec!["ïcode", "✓ passed", "✗"]
+        );
+    }
+
+    #[test]
+    fn ansi_line_style() {
+        let line_highlights = |spans| LineHighlights {
+            style: Some(HighlightStyle {
+                bg: Colour::Indexed(4),
+                ..HighlightStyle::default()
+            }),
+            spans,
+            style_rank: Rank::default(),
+        };
+
+        // The line background is kept within highlights which only set the foreground, or nothing.
+        assert_eq!(
+            ansi_line("abcdef", &line_highlights(vec![highlight(2, 4, 0, 0, 1)])),
+            "\x1b[48;5;4mab\x1b[38;5;1mcd\x1b[0m\x1b[48;5;4mef\x1b[0m"
+        );
+        assert_eq!(
+            ansi_line(
+                "abcdef",
+                &line_highlights(vec![Highlight {
+                    begin: 2,
+                    end: 4,
+                    ..Highlight::default()
+                }])
+            ),
+            "\x1b[48;5;4mabcd\x1b[0m\x1b[48;5;4mef\x1b[0m"
+        );
+    }
+
+    const NESTED_CONTEXTS: &str = r#"[
+        (enter: "^outer", exit: Some("^end outer"), matches: [(match: "x", colour: 1)]),
+        (enter: "^inner", exit: Some("^end inner"), matches: [(match: "x", colour: 2)]),
+    ]"#;
+
+    #[test]
+    fn nested_contexts() {
+        assert_eq!(
+            highlight_lines(
+                NESTED_CONTEXTS,
+                &[
+                    "x",
+                    "outer x",
+                    "x",
+                    "inner x",
+                    "x",
+                    "end inner x",
+                    "x",
+                    "end outer x",
+                    "x"
+                ]
+            ),
+            vec![
+                "x",
+                "outer x",
+                "[x:1]",
+                "inner x",
+                "[x:2]",
+                "end inner x",
+                "[x:1]",
+                "end outer x",
+                "x",
+            ]
+        );
+
+        // Exiting the outer context exits the inner one too.
+        assert_eq!(
+            highlight_lines(NESTED_CONTEXTS, &["outer", "inner", "x", "end outer", "x"]),
+            vec!["outer", "inner", "[x:2]", "end outer", "x"]
+        );
+    }
+
+    #[test]
+    fn reentered_contexts() {
+        // Entering a context already on the stack returns to it, exiting the inner one.
+        assert_eq!(
+            highlight_lines(
+                NESTED_CONTEXTS,
+                &[
+                    "outer",
+                    "inner",
+                    "x",
+                    "outer",
+                    "x",
+                    "end inner",
+                    "x",
+                    "end outer",
+                    "x"
+                ]
+            ),
+            vec![
+                "outer",
+                "inner",
+                "[x:2]",
+                "outer",
+                "[x:1]",
+                "end inner",
+                "[x:1]",
+                "end outer",
+                "x"
+            ]
+        );
+    }
+
+    #[test]
+    fn shared_exit_pattern() {
+        // When nested contexts exit on the same pattern only the innermost is exited, so the outer
+        // context needs another blank line, as in the README example.
+        let config = r#"[
+            (enter: " login failures:$", exit: Some("^$"), matches: [(match: "user", colour: 1)]),
+            (enter: "^Disk status", exit: Some("^$"), matches: [(match: "\\d+%", colour: 2)]),
+        ]"#;
+        assert_eq!(
+            highlight_lines(
+                config,
+                &[
+                    "sshd login failures:",
+                    "user",
+                    "Disk status",
+                    "user 95%",
+                    "",
+                    "user 95%",
+                    "",
+                    "user 95%",
+                ]
+            ),
+            vec![
+                "sshd login failures:",
+                "[user:1]",
+                "Disk status",
+                "user [95%:2]",
+                "",
+                "[user:1] 95%",
+                "",
+                "user 95%",
+            ]
+        );
+    }
+
+    #[test]
+    fn inherited_matches() {
+        let config = |inherit| {
+            format!(
+                r#"[
+                    (enter: "^outer", matches: [(match: "x", colour: 1), (match: "y", colour: 1)]),
+                    (enter: "^inner", inherit: {}, matches: [(match: "y", colour: 2)]),
+                ]"#,
+                inherit
+            )
+        };
+
+        // The inner context's matches win where they overlap.
+        assert_eq!(
+            highlight_lines(&config(true), &["outer", "inner", "x y"]),
+            vec!["outer", "inner", "[x:1] [y:2]"]
+        );
+        assert_eq!(
+            highlight_lines(&config(false), &["outer", "inner", "x y"]),
+            vec!["outer", "inner", "x [y:2]"]
+        );
+    }
+
+    #[test]
+    fn highlighted_bounds() {
+        let config = |highlight_bounds| {
+            format!(
+                r#"[(
+                    enter: "^begin",
+                    exit: Some("^end"),
+                    highlight_bounds: {},
+                    matches: [(match: "b", colour: 1)],
+                )]"#,
+                highlight_bounds
+            )
+        };
+
+        assert_eq!(
+            highlight_lines(&config(true), &["begin b", "b", "end b", "b"]),
+            vec!["[b:1]egin [b:1]", "[b:1]", "end [b:1]", "b"]
+        );
+        assert_eq!(
+            highlight_lines(&config(false), &["begin b", "b", "end b", "b"]),
+            vec!["begin b", "[b:1]", "end b", "b"]
+        );
+    }
+
+    #[test]
+    fn global_contexts() {
+        // Global contexts apply alongside any other context until they're exited.
+        let config = r#"[
+            (matches: [(match: "g", colour: 3)]),
+            (exit: Some("^stop"), matches: [(match: "h", colour: 4)]),
+            (enter: "^in", matches: [(match: "x", colour: 1)]),
+        ]"#;
+        assert_eq!(
+            highlight_lines(
+                config,
+                &["g h x", "in", "g h x", "stop g h", "g h x", "in", "h"]
+            ),
+            vec![
+                "[g:3] [h:4] x",
+                "in",
+                "[g:3] [h:4] [x:1]",
+                "stop [g:3] h",
+                "[g:3] h [x:1]",
+                "in",
+                "h",
+            ]
+        );
     }
 }
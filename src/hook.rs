@@ -0,0 +1,215 @@
+use crate::{mbox, query::Query};
+
+use std::{fs, io::Write, path::PathBuf, process::Stdio, thread::JoinHandle};
+
+// -------------------------------------------------------------------------------------------------
+// Hooks are shell commands run for individual messages, which are given the raw message on stdin
+// and its headers in `SMBOX_<NAME>` environment variables, e.g., `SMBOX_SUBJECT`.  They're
+// configured in the `hooks` section of the config file to be run for newly delivered messages
+// which match a query.
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub(crate) struct Hook {
+    query: Query,
+    command: String,
+}
+
+// Run every hook whose query matches the message, reporting any which fail.
+pub(crate) fn run_matching(hooks: &[Hook], msg: &mbox::Message, quiet: bool) -> anyhow::Result<()> {
+    run_all(matching_runs(hooks, msg).collect(), quiet)
+}
+
+// Run every hook whose query matches any of the messages in a separate thread, so the TUI isn't
+// held up.  The thread quietly runs each in turn and is joined for its result once finished.
+pub(crate) fn spawn_matching<'a>(
+    hooks: &[Hook],
+    msgs: impl IntoIterator<Item = &'a mbox::Message>,
+) -> Option<JoinHandle<anyhow::Result<()>>> {
+    let runs = msgs
+        .into_iter()
+        .flat_map(|msg| matching_runs(hooks, msg))
+        .collect::<Vec<_>>();
+
+    (!runs.is_empty()).then(|| std::thread::spawn(move || run_all(runs, true)))
+}
+
+// A hook command along with everything it's given for a particular message.
+struct HookRun {
+    command: String,
+    input: Vec<String>,
+    envs: Vec<(String, String)>,
+}
+
+fn matching_runs<'a>(
+    hooks: &'a [Hook],
+    msg: &'a mbox::Message,
+) -> impl Iterator<Item = HookRun> + 'a {
+    hooks
+        .iter()
+        .filter(move |hook| hook.query.matches(msg))
+        .map(move |hook| HookRun {
+            command: hook.command.clone(),
+            input: msg.all_lines().to_vec(),
+            envs: header_env_vars(msg),
+        })
+}
+
+fn run_all(runs: Vec<HookRun>, quiet: bool) -> anyhow::Result<()> {
+    let errors = runs
+        .into_iter()
+        .filter_map(|run| run_with_input(&run.command, &run.input, run.envs, quiet).err())
+        .map(|err| err.to_string())
+        .collect::<Vec<_>>();
+
+    if !errors.is_empty() {
+        anyhow::bail!("{}", errors.join("  "));
+    }
+    Ok(())
+}
+
+// Only one smbox at a time runs the hooks for a mailbox, otherwise with the TUI open and
+// `smbox watch` running every hook would be run twice.  Whichever first takes an exclusive lock on a
+// file named for the mailbox runs them until it exits, at which point the lock is free for another.
+pub(crate) struct HookLock {
+    path: Option<PathBuf>,
+    file: Option<fs::File>,
+}
+
+impl HookLock {
+    pub(crate) fn new(mbox_path: &str) -> Self {
+        let path = directories::BaseDirs::new().map(|base_dirs| {
+            let mbox_path = fs::canonicalize(mbox_path).unwrap_or_else(|_| mbox_path.into());
+            base_dirs
+                .runtime_dir()
+                .unwrap_or(base_dirs.cache_dir())
+                .join(format!(
+                    "smbox-hooks-{:016x}.lock",
+                    fxhash::hash64(&mbox_path)
+                ))
+        });
+
+        HookLock { path, file: None }
+    }
+
+    // Whether hooks are to be run here, taking the lock if it's free.  If the lock file can't be
+    // used at all the hooks are run regardless, rather than silently dropped.
+    pub(crate) fn acquire(&mut self) -> bool {
+        if self.file.is_some() {
+            return true;
+        }
+        let Some(path) = &self.path else {
+            return true;
+        };
+
+        if let Some(dir) = path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        match fs::OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => match file.try_lock() {
+                Ok(()) => {
+                    self.file = Some(file);
+                    true
+                }
+                Err(fs::TryLockError::WouldBlock) => false,
+                Err(fs::TryLockError::Error(_)) => true,
+            },
+            Err(_) => true,
+        }
+    }
+}
+
+// Run a command for a message.  When `quiet` its output is discarded, except for the first line of
+// any error, so it doesn't interfere with the TUI.
+pub(crate) fn run(command: &str, msg: &mbox::Message, quiet: bool) -> anyhow::Result<()> {
+    run_with_input(command, msg.all_lines(), header_env_vars(msg), quiet)
+}
+
+// Run a shell command with some lines on its stdin, e.g., a hook or a message piped from the TUI.
+pub(crate) fn run_with_input<'a>(
+    command: &str,
+    input: impl IntoIterator<Item = &'a String>,
+    envs: Vec<(String, String)>,
+    quiet: bool,
+) -> anyhow::Result<()> {
+    let (stdout, stderr) = if quiet {
+        (Stdio::null(), Stdio::piped())
+    } else {
+        (Stdio::inherit(), Stdio::inherit())
+    };
+
+    let mut child = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .envs(envs)
+        .stdin(Stdio::piped())
+        .stdout(stdout)
+        .stderr(stderr)
+        .spawn()?;
+
+    // The input is written from another thread while the error output is read here, otherwise a
+    // command writing a lot of errors before reading its input would wait on us forever.
+    let writer = child.stdin.take().map(|mut stdin| {
+        let input = input.into_iter().fold(String::new(), |mut input, line| {
+            input.push_str(line);
+            input.push('\n');
+            input
+        });
+
+        std::thread::spawn(move || {
+            // The command may choose not to read all of its input, so ignore any errors here.
+            let _ = stdin.write_all(input.as_bytes());
+        })
+    });
+
+    let output = child.wait_with_output()?;
+    if let Some(writer) = writer {
+        let _ = writer.join();
+    }
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        match stderr.lines().next() {
+            Some(err_line) => anyhow::bail!("'{command}' failed ({}): {err_line}", output.status),
+            None => anyhow::bail!("'{command}' failed ({}).", output.status),
+        }
+    }
+
+    Ok(())
+}
+
+// Each header as an environment variable named for it, e.g., 'X-Cron-Env' becomes
+// `SMBOX_X_CRON_ENV`.  Folded header lines are joined and only the first of any repeated headers
+// is used.
+fn header_env_vars(msg: &mbox::Message) -> Vec<(String, String)> {
+    let mut vars: Vec<(String, String)> = Vec::new();
+    let mut is_repeat = false;
+
+    for line in msg.header_lines() {
+        if line.starts_with([' ', '\t']) {
+            if let (Some((_, value)), false) = (vars.last_mut(), is_repeat) {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            let var_name = format!(
+                "SMBOX_{}",
+                name.trim()
+                    .chars()
+                    .map(|ch| if ch.is_ascii_alphanumeric() {
+                        ch.to_ascii_uppercase()
+                    } else {
+                        '_'
+                    })
+                    .collect::<String>()
+            );
+
+            is_repeat = vars.iter().any(|(existing, _)| *existing == var_name);
+            if !is_repeat {
+                vars.push((var_name, value.trim().to_owned()));
+            }
+        }
+    }
+
+    vars
+}
+
+// -------------------------------------------------------------------------------------------------
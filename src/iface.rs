@@ -1,189 +1,1057 @@
 use crate::{
-    highlight::{Highlight, HighlightConfig},
+    config::Config,
+    highlight::{self, Colour, Highlight, HighlightStyle, LineHighlights},
+    hook,
+    keymap::{Action, KeyChord, Lookup},
     mbox,
+    query::Query,
+    watch::{MboxChange, MboxWatcher},
+};
+
+use std::{
+    io::Write,
+    thread::JoinHandle,
+    time::{Duration, Instant, SystemTime},
 };
 
 use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use fxhash::FxHashSet;
 use ratatui::{
     prelude::*,
     symbols::scrollbar,
     widgets::{
-        Block, Paragraph, Row, Scrollbar, ScrollbarOrientation, ScrollbarState, Table, TableState,
-        Widget, Wrap,
+        Block, Cell, Clear, Padding, Paragraph, Row, Scrollbar, ScrollbarOrientation,
+        ScrollbarState, Table, TableState, Widget, Wrap,
     },
     DefaultTerminal,
 };
 
 pub(crate) fn run(
     messages: mbox::Mbox,
-    highlighter: HighlightConfig,
-) -> anyhow::Result<Option<mbox::Mbox>> {
+    config: Config,
+    mbox_path: String,
+    mbox_mtime: SystemTime,
+    mbox_len: u64,
+    read_only: bool,
+) -> anyhow::Result<Option<(mbox::Mbox, SystemTime)>> {
     let mut terminal = ratatui::init();
     // XXX: use https://docs.rs/ratatui/latest/ratatui/struct.Terminal.html#method.clear here
-    let result = IfaceState::new(messages, highlighter).run(&mut terminal);
+    let watcher = MboxWatcher::new(&mbox_path, mbox_len);
+    let result = IfaceState::new(messages, config, mbox_path, mbox_mtime, watcher, read_only)
+        .run(&mut terminal);
     ratatui::restore();
     result
 }
 
 struct IfaceState {
     mbox: mbox::Mbox,
-    highlight_config: HighlightConfig,
+    config: Config,
+    mbox_path: String,
+    read_only: bool,
+
+    // When the mailbox was last read or written by us, to detect it being changed by others, and
+    // whether it has been, in which case it can't be saved.
+    mbox_mtime: SystemTime,
+    rewritten: bool,
+
+    // Watching for new mail, and the index of the first new message yet to be looked at.
+    watcher: MboxWatcher,
+    new_mail_idx: Option<usize>,
+
+    // Hooks for new mail run in the background, unless another smbox is running them.
+    hook_lock: hook::HookLock,
+    hook_runs: Vec<JoinHandle<anyhow::Result<()>>>,
+
+    // The status of each message when loaded, to find what has changed since.
+    orig_statuses: Vec<Vec<mbox::Status>>,
+
     finished: Option<ExitType>,
     selector: TableState,
     scrollbar: ScrollbarState,
     scroll_count: usize,
+    body_height: usize,
+    body_line_count: usize,
     wrap: bool,
+
+    // The highlighted body of the selected message, so it's only highlighted again when needed.
+    body_cache: Option<BodyCache>,
+
+    // The highlighted message list row for each message in `mbox`, by index, so each is only
+    // highlighted again when its status changes rather than on every draw.
+    row_cache: Vec<Option<RowCache>>,
+
+    // Indices into `mbox` for the messages currently visible, i.e., those matching the filter.
+    view: Vec<usize>,
+    filter: Option<Query>,
+    prompt: Option<Prompt>,
+    notice: Option<Notice>,
+
+    // The scroll position of the help overlay, while it's open.
+    help_scroll: Option<usize>,
+
+    // How we're quitting while waiting for confirmation.
+    confirm_exit: Option<ExitType>,
+
+    // Indices into `mbox` for tagged messages, and whether the next action applies to them.
+    tagged: FxHashSet<usize>,
+    last_tagged: Option<usize>,
+    tag_prefix: bool,
+
+    // Keys pressed so far in a multi-key sequence, and any numeric count prefix before them.
+    pending_keys: Vec<KeyChord>,
+    count: Option<usize>,
+
+    // The selected message and when it was selected, if it's yet to be marked read.
+    read_pending: Option<(usize, Instant)>,
+    mark_read_delay: Duration,
+}
+
+struct BodyCache {
+    msg_idx: usize,
+
+    // Highlight contexts may be scoped by status, and styled lines are filled to the body width
+    // when they aren't wrapped, so these must match too.
+    status_summary: String,
+    wrap: bool,
+    body_width: usize,
+
+    lines: Vec<Line<'static>>,
+}
+
+struct RowCache {
+    // Row and header rules may match by status.
+    statuses: Vec<mbox::Status>,
+
+    style: Option<Style>,
+    date: Line<'static>,
+    from: Line<'static>,
+    subject: Line<'static>,
 }
 
+// The number of messages whose status has been changed each way since they were loaded.
+#[derive(Default)]
+struct Changes {
+    deleted: usize,
+    undeleted: usize,
+    marked_read: usize,
+    marked_unread: usize,
+    flagged: usize,
+    unflagged: usize,
+}
+
+impl Changes {
+    fn is_empty(&self) -> bool {
+        self.deleted
+            + self.undeleted
+            + self.marked_read
+            + self.marked_unread
+            + self.flagged
+            + self.unflagged
+            == 0
+    }
+}
+
+impl std::fmt::Display for Changes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let summary = [
+            (self.deleted, "deleted"),
+            (self.undeleted, "undeleted"),
+            (self.marked_read, "marked read"),
+            (self.marked_unread, "marked unread"),
+            (self.flagged, "flagged"),
+            (self.unflagged, "unflagged"),
+        ]
+        .iter()
+        .filter(|(count, _)| *count > 0)
+        .map(|(count, change)| format!("{count} {change}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+        write!(f, "{summary}")
+    }
+}
+
+fn statuses(msg: &mbox::Message) -> Vec<mbox::Status> {
+    mbox::Status::ALL
+        .iter()
+        .copied()
+        .filter(|status| msg.has_status(*status))
+        .collect()
+}
+
+#[derive(Clone, Copy)]
 enum ExitType {
     NoChange,
     Update,
 }
 
-const SCROLL_LINES_COUNT: usize = 24;
+struct Prompt {
+    kind: PromptKind,
+    text: String,
+}
+
+enum PromptKind {
+    Search,
+    TagQuery,
+    Save(Vec<usize>),
+    Pipe(Vec<usize>),
+}
+
+impl PromptKind {
+    fn label(&self) -> &'static str {
+        match self {
+            PromptKind::Search => "/",
+            PromptKind::TagQuery => "Tag matching: ",
+            PromptKind::Save(_) => "Save to file: ",
+            PromptKind::Pipe(_) => "Pipe to command: ",
+        }
+    }
+}
+
+enum Notice {
+    Info(String),
+    Error(String),
+}
+
+#[derive(Clone, Copy)]
+enum StatusAction {
+    Delete,
+    Undelete,
+    MarkRead,
+    MarkUnread,
+    ToggleRead,
+    ToggleFlagged,
+}
 
 impl IfaceState {
-    fn new(mbox: mbox::Mbox, highlighter: HighlightConfig) -> IfaceState {
+    fn new(
+        mbox: mbox::Mbox,
+        config: Config,
+        mbox_path: String,
+        mbox_mtime: SystemTime,
+        watcher: MboxWatcher,
+        read_only: bool,
+    ) -> IfaceState {
         IfaceState {
-            mbox,
-            highlight_config: highlighter,
+            orig_statuses: mbox.iter().map(statuses).collect(),
+            hook_lock: hook::HookLock::new(&mbox_path),
+            mbox_path,
+            mbox_mtime,
+            watcher,
+            new_mail_idx: None,
+            hook_runs: Vec::new(),
+            read_only,
+            rewritten: false,
+            mark_read_delay: config.mark_read_delay(),
+            config,
             finished: None,
             selector: Default::default(),
             scrollbar: Default::default(),
             scroll_count: 0,
+            body_height: 0,
+            body_line_count: 0,
             wrap: false,
+            body_cache: None,
+            row_cache: Vec::new(),
+            view: (0..mbox.count()).collect(),
+            filter: None,
+            prompt: None,
+            notice: None,
+            help_scroll: None,
+            confirm_exit: None,
+            tagged: FxHashSet::default(),
+            last_tagged: None,
+            tag_prefix: false,
+            pending_keys: Vec::default(),
+            count: None,
+            read_pending: None,
+            mbox,
         }
     }
 
-    fn run(mut self, terminal: &mut DefaultTerminal) -> anyhow::Result<Option<mbox::Mbox>> {
+    fn run(
+        mut self,
+        terminal: &mut DefaultTerminal,
+    ) -> anyhow::Result<Option<(mbox::Mbox, SystemTime)>> {
         self.selector.select_first();
-        self.set_selected_status(mbox::Status::Read);
+        self.start_reading();
+        self.hook_lock.acquire();
 
         while self.finished.is_none() {
             terminal.draw(|frame| frame.render_widget(&mut self, frame.area()))?;
-            self.handle_events()?;
+
+            // Only wait for input until it's time to check for new mail, or for as long as the
+            // pending read allows.
+            let mut timeout = self.watcher.interval();
+            if let Some((_, since)) = self.read_pending {
+                timeout = timeout
+                    .min((since + self.mark_read_delay).saturating_duration_since(Instant::now()));
+            }
+            if event::poll(timeout)? {
+                self.handle_events()?;
+            }
+
+            self.check_for_new_mail();
+            self.check_hook_runs();
+
+            if self
+                .read_pending
+                .is_some_and(|(_, since)| since.elapsed() >= self.mark_read_delay)
+            {
+                self.finish_reading();
+            }
+        }
+
+        // Let any hooks still running finish rather than cutting off their input.  It's too late
+        // to report any errors though.
+        for hook_run in self.hook_runs.drain(..) {
+            let _ = hook_run.join();
         }
 
         match self.finished {
-            Some(ExitType::Update) => Ok(Some(self.mbox)),
+            Some(ExitType::Update) => Ok(Some((self.mbox, self.mbox_mtime))),
             Some(ExitType::NoChange) | None => Ok(None),
         }
     }
 
     fn handle_events(&mut self) -> anyhow::Result<()> {
         match event::read()? {
-            Event::Key(key) if key.kind == KeyEventKind::Press => match key.code {
-                KeyCode::Char('q') => {
-                    self.finished = Some(ExitType::Update);
+            Event::Key(key) if key.kind == KeyEventKind::Press && self.prompt.is_some() => {
+                self.handle_prompt_key(key.code)
+            }
+
+            Event::Key(key) if key.kind == KeyEventKind::Press && self.confirm_exit.is_some() => {
+                self.handle_confirm_key(key.code)
+            }
+
+            Event::Key(key) if key.kind == KeyEventKind::Press && self.help_scroll.is_some() => {
+                self.handle_help_key(key.code)
+            }
+
+            Event::Key(key) if key.kind == KeyEventKind::Press => {
+                self.notice = None;
+
+                // Digits before a key sequence are a count, unless they're bound themselves.  Once
+                // a count is started every digit is part of it, including '0'.
+                if let (KeyCode::Char(ch @ '0'..='9'), true) = (
+                    key.code,
+                    self.pending_keys.is_empty() && key.modifiers.is_empty(),
+                ) {
+                    let is_bound = !matches!(
+                        self.config.keys.lookup(&[KeyChord::from(key)]),
+                        Lookup::Unbound
+                    );
+                    if self.count.is_some() || !is_bound {
+                        // A count is never any use beyond the number of messages or body lines,
+                        // and limiting it stops it overflowing or repeating an action for ages.
+                        let limit = self.view.len().max(self.body_line_count).max(1);
+                        let digit = ch.to_digit(10).unwrap() as usize;
+                        let count = self
+                            .count
+                            .unwrap_or(0)
+                            .saturating_mul(10)
+                            .saturating_add(digit)
+                            .min(limit);
+                        self.count = Some(count);
+                        self.notice = Some(Notice::Info(count.to_string()));
+                        return Ok(());
+                    }
                 }
-                KeyCode::Char('x') => {
-                    self.finished = Some(ExitType::NoChange);
+
+                self.pending_keys.push(KeyChord::from(key));
+
+                match self.config.keys.lookup(&self.pending_keys) {
+                    Lookup::Action(action) => {
+                        self.pending_keys.clear();
+
+                        // The tag prefix and count only apply to the very next action.
+                        let tag_prefix = std::mem::take(&mut self.tag_prefix);
+                        let count = self.count.take();
+                        self.perform(action, tag_prefix, count);
+                    }
+                    Lookup::Prefix => {
+                        if let Some(count) = self.count {
+                            self.notice = Some(Notice::Info(count.to_string()));
+                        }
+                    }
+                    Lookup::Unbound => {
+                        self.pending_keys.clear();
+                        self.tag_prefix = false;
+                        self.count = None;
+                    }
                 }
+            }
+
+            _ => {}
+        }
 
-                KeyCode::Char('j') => {
-                    self.select_next();
-                    self.set_selected_status(mbox::Status::Read);
+        Ok(())
+    }
+
+    // Perform an action, `count` times for those where that makes sense.  A count for the first
+    // and last message actions selects that message number instead.
+    fn perform(&mut self, action: Action, tag_prefix: bool, count: Option<usize>) {
+        let repeat = count.unwrap_or(1).max(1);
+        let half_page = (self.body_height / 2).max(1);
+        let signed = |offset: usize| offset.min(isize::MAX as usize) as isize;
+
+        match action {
+            // Nothing can be saved to a read-only or rewritten mailbox, so rather than failing once
+            // the screen has closed and losing every change, only exiting is allowed.
+            Action::Quit | Action::Sync if self.read_only || self.rewritten => {
+                let mut msg = if self.read_only {
+                    "The mailbox is read-only and can't be saved.".to_owned()
+                } else {
+                    "The mailbox has been changed by something else and can't be saved.".to_owned()
+                };
+                if let Some(key) = self.config.keys.keys_for(Action::Exit).first() {
+                    msg.push_str(&format!("  Use '{key}' to exit without saving."));
                 }
-                KeyCode::Char('k') => {
-                    self.select_prev();
-                    self.set_selected_status(mbox::Status::Read);
+                self.notice = Some(Notice::Error(msg));
+            }
+            Action::Quit => self.quit(ExitType::Update),
+            Action::Exit => self.quit(ExitType::NoChange),
+            Action::Sync => {
+                if let Err(err) = self.sync() {
+                    self.notice = Some(Notice::Error(err.to_string()));
                 }
+            }
 
-                KeyCode::Char(' ') => {
-                    self.scroll_count = self.scroll_count.saturating_add(SCROLL_LINES_COUNT);
+            Action::NextMessage => {
+                self.select_offset(signed(repeat));
+                self.start_reading();
+            }
+            Action::PrevMessage => {
+                self.select_offset(-signed(repeat));
+                self.start_reading();
+            }
+            Action::FirstMessage | Action::LastMessage => {
+                let view_idx = match (count, action) {
+                    (Some(number), _) => number.saturating_sub(1),
+                    (None, Action::FirstMessage) => 0,
+                    (None, _) => usize::MAX,
+                };
+                self.select(view_idx.min(self.view.len().saturating_sub(1)));
+                self.start_reading();
+            }
+
+            Action::PageDown => {
+                self.scroll_by(signed(self.body_height.max(1).saturating_mul(repeat)))
+            }
+            Action::PageUp => {
+                self.scroll_by(-signed(self.body_height.max(1).saturating_mul(repeat)))
+            }
+            Action::HalfPageDown => self.scroll_by(signed(half_page.saturating_mul(repeat))),
+            Action::HalfPageUp => self.scroll_by(-signed(half_page.saturating_mul(repeat))),
+            Action::LineDown => self.scroll_by(signed(repeat)),
+            Action::LineUp => self.scroll_by(-signed(repeat)),
+            Action::BodyTop => {
+                self.scroll_count = 0;
+            }
+
+            Action::ToggleWrap => {
+                self.wrap = !self.wrap;
+            }
+
+            Action::Delete if !tag_prefix => {
+                for _ in 0..repeat {
+                    self.set_selected_status(mbox::Status::Deleted);
+                    self.select_offset(1);
                 }
-                KeyCode::Char('b') => {
-                    self.scroll_count = self.scroll_count.saturating_sub(SCROLL_LINES_COUNT);
+                self.start_reading();
+            }
+            Action::Delete => self.apply_status_action(StatusAction::Delete, true),
+            Action::Undelete => self.apply_status_action(StatusAction::Undelete, tag_prefix),
+            Action::MarkRead => self.apply_status_action(StatusAction::MarkRead, tag_prefix),
+            Action::MarkUnread => self.apply_status_action(StatusAction::MarkUnread, tag_prefix),
+            Action::ToggleRead => self.apply_status_action(StatusAction::ToggleRead, tag_prefix),
+            Action::ToggleFlagged => {
+                self.apply_status_action(StatusAction::ToggleFlagged, tag_prefix)
+            }
+            Action::DeleteVisible => {
+                // Delete every message in the view, which is all of them when unfiltered.
+                for idx in &self.view {
+                    if let Some(msg) = self.mbox.msg_at_mut(*idx) {
+                        msg.set_status(mbox::Status::Deleted);
+                    }
                 }
-                KeyCode::Char('g') => {
-                    self.scroll_count = 0;
+            }
+
+            Action::Save => {
+                let targets = self.action_targets(tag_prefix);
+                self.open_prompt(PromptKind::Save(targets), String::new());
+            }
+            Action::Pipe => {
+                let targets = self.action_targets(tag_prefix);
+                self.open_prompt(PromptKind::Pipe(targets), String::new());
+            }
+
+            Action::ToggleTag => {
+                for _ in 0..repeat {
+                    if let Some(idx) = self.selected_msg_idx() {
+                        if !self.tagged.remove(&idx) {
+                            self.tagged.insert(idx);
+                            self.last_tagged = Some(idx);
+                        }
+                    }
+                    self.select_offset(1);
+                }
+                self.start_reading();
+            }
+            Action::TagRange => self.tag_range(),
+            Action::TagAllVisible => {
+                // Tag every visible message, or untag them if they're already all tagged.
+                if self.view.iter().all(|idx| self.tagged.contains(idx)) {
+                    for idx in &self.view {
+                        self.tagged.remove(idx);
+                    }
+                } else {
+                    self.tagged.extend(self.view.iter().copied());
                 }
+            }
+            Action::TagMatching => self.open_prompt(PromptKind::TagQuery, String::new()),
+            Action::TagPrefix => {
+                if self.tagged.is_empty() {
+                    self.notice = Some(Notice::Error("No tagged messages.".to_owned()));
+                } else {
+                    self.tag_prefix = true;
+                    self.notice = Some(Notice::Info(format!(
+                        "Apply the next action to {} tagged messages.",
+                        self.tagged.len()
+                    )));
+                }
+            }
 
-                KeyCode::Char('s') => {
-                    self.wrap = !self.wrap;
+            Action::Search => {
+                let filter_str = self
+                    .filter
+                    .as_ref()
+                    .map(|query| query.to_string())
+                    .unwrap_or_default();
+                self.open_prompt(PromptKind::Search, filter_str);
+            }
+            Action::ClearFilter => {
+                self.set_filter(None);
+            }
+
+            Action::Help => {
+                self.help_scroll = Some(0);
+            }
+        }
+    }
+
+    // Quit straight away unless there are changes and we're to confirm them first.
+    fn quit(&mut self, exit_type: ExitType) {
+        if self.config.confirm_quit && !self.pending_changes().is_empty() {
+            self.confirm_exit = Some(exit_type);
+        } else {
+            self.finished = Some(exit_type);
+        }
+    }
+
+    // Merge any newly delivered messages, leaving everything else as it is.
+    fn check_for_new_mail(&mut self) {
+        match self.watcher.check() {
+            Ok(None) => (),
+            Ok(Some(MboxChange::Appended(lines, mbox_mtime))) => {
+                let new_mbox = lines.into_iter().collect::<mbox::Mbox>();
+                let num_new = new_mbox.count();
+
+                if self.hook_lock.acquire() {
+                    self.hook_runs
+                        .extend(hook::spawn_matching(&self.config.hooks, new_mbox.iter()));
                 }
 
-                KeyCode::Char('d') => {
-                    self.set_selected_status(mbox::Status::Deleted);
-                    self.select_next();
-                    self.set_selected_status(mbox::Status::Read);
+                self.new_mail_idx = self.new_mail_idx.or(Some(self.mbox.count()));
+                self.orig_statuses.extend(new_mbox.iter().map(statuses));
+                self.mbox.append(new_mbox);
+                self.mbox_mtime = mbox_mtime;
+                self.update_view(self.selected_msg_idx());
+
+                if self.prompt.is_none() {
+                    self.notice = Some(Notice::Info(match num_new {
+                        1 => "1 new message has arrived.".to_owned(),
+                        n => format!("{n} new messages have arrived."),
+                    }));
                 }
+            }
+            Ok(Some(MboxChange::Rewritten)) => {
+                self.rewritten = true;
+                self.notice = Some(Notice::Error(
+                    "The mailbox has been changed by something else and can't be saved.".to_owned(),
+                ));
+            }
+            Err(err) => {
+                self.notice = Some(Notice::Error(format!(
+                    "Failed to check for new mail: {err}"
+                )))
+            }
+        }
+    }
 
-                _ => (),
-            },
+    // Report any hooks which have failed once they've finished.
+    fn check_hook_runs(&mut self) {
+        let (finished, running) = std::mem::take(&mut self.hook_runs)
+            .into_iter()
+            .partition::<Vec<_>, _>(|hook_run| hook_run.is_finished());
+        self.hook_runs = running;
 
-            _ => {}
+        let hook_errors = finished
+            .into_iter()
+            .filter_map(|hook_run| match hook_run.join() {
+                Ok(result) => result.err(),
+                Err(_) => Some(anyhow::anyhow!("A hook thread panicked.")),
+            })
+            .map(|err| err.to_string())
+            .collect::<Vec<_>>();
+
+        if !hook_errors.is_empty() {
+            self.notice = Some(Notice::Error(hook_errors.join("  ")));
         }
+    }
+
+    // Write the changes so far, the same as when quitting, and then reload the mailbox to carry on
+    // with whatever is left.
+    fn sync(&mut self) -> anyhow::Result<()> {
+        self.mbox.mark_all_seen();
+        let num_deleted = mbox::write_mbox(&self.mbox, self.mbox_mtime)?;
+
+        // Messages are written in order without the deleted ones, so the survivors are simply
+        // renumbered.
+        let mut next_idx = 0;
+        let new_idcs = self
+            .mbox
+            .iter()
+            .map(|msg| {
+                (!msg.has_status(mbox::Status::Deleted)).then(|| {
+                    next_idx += 1;
+                    next_idx - 1
+                })
+            })
+            .collect::<Vec<_>>();
+        let new_idx = |idx: usize| new_idcs.get(idx).copied().flatten();
+
+        // Stay on the selected message, or the nearest one to it if it was deleted.
+        let selected_idx = self.selected_msg_idx().and_then(|sel_idx| {
+            (sel_idx..new_idcs.len())
+                .chain((0..sel_idx).rev())
+                .find_map(new_idx)
+        });
+
+        let (lines, mbox_mtime, mbox_len) = mbox::read_mbox_lines(0)?;
+        let new_mbox = lines.into_iter().collect::<mbox::Mbox>();
 
+        self.tagged = self.tagged.iter().filter_map(|idx| new_idx(*idx)).collect();
+        self.last_tagged = self.last_tagged.and_then(new_idx);
+        self.read_pending = self
+            .read_pending
+            .and_then(|(idx, since)| new_idx(idx).map(|idx| (idx, since)));
+
+        self.orig_statuses = new_mbox.iter().map(statuses).collect();
+        self.mbox = new_mbox;
+        self.mbox_mtime = mbox_mtime;
+        self.body_cache = None;
+        self.row_cache.clear();
+        self.watcher.reset(mbox_len);
+        self.new_mail_idx = self.new_mail_idx.and_then(new_idx);
+        self.update_view(selected_idx);
+
+        self.notice = Some(Notice::Info(match num_deleted {
+            1 => "Saved changes, deleted 1 message.".to_owned(),
+            n => format!("Saved changes, deleted {n} messages."),
+        }));
         Ok(())
     }
 
-    fn select_next(&mut self) {
-        self.selector.select_next();
-        self.scroll_count = 0;
+    fn handle_confirm_key(&mut self, key_code: KeyCode) {
+        match key_code {
+            KeyCode::Char('y') | KeyCode::Enter => self.finished = self.confirm_exit,
+            KeyCode::Char('r') => {
+                // Review the deleted messages by filtering for them.
+                match Query::parse("deleted") {
+                    Ok(query) => self.set_filter(Some(query)),
+                    Err(err) => self.notice = Some(Notice::Error(err.to_string())),
+                }
+            }
+            _ => (),
+        }
+        self.confirm_exit = None;
+    }
+
+    // The help overlay may be scrolled, but any other key closes it.
+    fn handle_help_key(&mut self, key_code: KeyCode) {
+        let line_count = self.help_lines().len();
+        if let Some(scroll) = self.help_scroll.as_mut() {
+            match key_code {
+                KeyCode::Char('j') | KeyCode::Down => {
+                    *scroll = (*scroll + 1).min(line_count.saturating_sub(1))
+                }
+                KeyCode::Char('k') | KeyCode::Up => *scroll = scroll.saturating_sub(1),
+                _ => self.help_scroll = None,
+            }
+        }
     }
 
-    fn select_prev(&mut self) {
-        self.selector.select_previous();
+    fn open_prompt(&mut self, kind: PromptKind, text: String) {
+        self.notice = None;
+        self.prompt = Some(Prompt { kind, text });
+    }
+
+    fn handle_prompt_key(&mut self, key_code: KeyCode) {
+        let Some(prompt) = self.prompt.as_mut() else {
+            return;
+        };
+
+        match key_code {
+            KeyCode::Char(ch) => prompt.text.push(ch),
+            KeyCode::Backspace => {
+                prompt.text.pop();
+            }
+            KeyCode::Esc => {
+                self.prompt = None;
+                self.notice = None;
+            }
+            KeyCode::Enter => {
+                let Prompt { kind, text } = self.prompt.take().unwrap();
+                let text = text.trim().to_owned();
+
+                self.notice = None;
+                let result = match &kind {
+                    PromptKind::Search if text.is_empty() => {
+                        self.set_filter(None);
+                        Ok(())
+                    }
+                    PromptKind::Search => Query::parse(&text).map(|query| {
+                        self.set_filter(Some(query));
+                    }),
+                    PromptKind::TagQuery => Query::parse(&text).map(|query| {
+                        let matching = self
+                            .view
+                            .iter()
+                            .copied()
+                            .filter(|idx| self.mbox.msg_at(*idx).is_some_and(|m| query.matches(m)))
+                            .collect::<Vec<_>>();
+                        self.notice = Some(Notice::Info(format!(
+                            "Tagged {} matching messages.",
+                            matching.len()
+                        )));
+                        self.tagged.extend(matching);
+                    }),
+                    PromptKind::Save(_) | PromptKind::Pipe(_) if text.is_empty() => Ok(()),
+                    PromptKind::Save(targets) => self.save_messages(targets, &text),
+                    PromptKind::Pipe(targets) => self.pipe_messages(targets, &text),
+                };
+
+                if let Err(err) = result {
+                    // Leave the prompt open so it can be fixed.
+                    self.notice = Some(Notice::Error(err.to_string()));
+                    self.prompt = Some(Prompt { kind, text });
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // The messages an action applies to: the tagged messages when following the tag prefix,
+    // otherwise the selected message.
+    fn action_targets(&self, tag_prefix: bool) -> Vec<usize> {
+        if tag_prefix {
+            let mut targets = self.tagged.iter().copied().collect::<Vec<_>>();
+            targets.sort_unstable();
+            targets
+        } else {
+            self.selected_msg_idx().into_iter().collect()
+        }
+    }
+
+    fn apply_status_action(&mut self, action: StatusAction, tag_prefix: bool) {
+        let targets = self.action_targets(tag_prefix);
+
+        // Toggling a group sets the status on them all unless they all have it already.
+        let all_have = |status| {
+            targets.iter().all(|idx| {
+                self.mbox
+                    .msg_at(*idx)
+                    .is_some_and(|msg| msg.has_status(status))
+            })
+        };
+        let all_read = all_have(mbox::Status::Read);
+        let all_flagged = all_have(mbox::Status::Flagged);
+
+        // An explicit change to the read status overrides any pending read.
+        if matches!(
+            action,
+            StatusAction::MarkRead | StatusAction::MarkUnread | StatusAction::ToggleRead
+        ) {
+            self.read_pending = None;
+        }
+
+        for idx in targets {
+            if let Some(msg) = self.mbox.msg_at_mut(idx) {
+                match action {
+                    StatusAction::Delete => msg.set_status(mbox::Status::Deleted),
+                    StatusAction::Undelete => msg.unset_status(mbox::Status::Deleted),
+                    StatusAction::MarkRead => msg.set_status(mbox::Status::Read),
+                    StatusAction::MarkUnread => msg.unset_status(mbox::Status::Read),
+                    StatusAction::ToggleRead if all_read => msg.unset_status(mbox::Status::Read),
+                    StatusAction::ToggleRead => msg.set_status(mbox::Status::Read),
+                    StatusAction::ToggleFlagged if all_flagged => {
+                        msg.unset_status(mbox::Status::Flagged)
+                    }
+                    StatusAction::ToggleFlagged => msg.set_status(mbox::Status::Flagged),
+                }
+            }
+        }
+    }
+
+    // Tag every visible message between the most recently tagged message and the selection.
+    fn tag_range(&mut self) {
+        let selected_view_idx = self.selector.selected().unwrap_or(0);
+        let Some(last_view_idx) = self
+            .last_tagged
+            .and_then(|last| self.view.iter().position(|idx| *idx == last))
+        else {
+            self.notice = Some(Notice::Error(
+                "No visible tagged message to start the range from.".to_owned(),
+            ));
+            return;
+        };
+
+        let first = selected_view_idx.min(last_view_idx);
+        let last = selected_view_idx.max(last_view_idx);
+        self.tagged
+            .extend(self.view.iter().take(last + 1).skip(first).copied());
+    }
+
+    fn save_messages(&mut self, targets: &[usize], path: &str) -> anyhow::Result<()> {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| anyhow::anyhow!("Failed to open '{path}': {err}"))?;
+
+        for msg in targets.iter().filter_map(|idx| self.mbox.msg_at(*idx)) {
+            for line in msg.all_lines() {
+                writeln!(file, "{line}")?;
+            }
+        }
+
+        self.notice = Some(Notice::Info(format!(
+            "Saved {} message(s) to '{path}'.",
+            targets.len()
+        )));
+        Ok(())
+    }
+
+    fn pipe_messages(&mut self, targets: &[usize], command: &str) -> anyhow::Result<()> {
+        hook::run_with_input(
+            command,
+            targets
+                .iter()
+                .filter_map(|idx| self.mbox.msg_at(*idx))
+                .flat_map(|msg| msg.all_lines()),
+            Vec::new(),
+            true,
+        )?;
+
+        self.notice = Some(Notice::Info(format!(
+            "Piped {} message(s) to '{command}'.",
+            targets.len()
+        )));
+        Ok(())
+    }
+
+    fn set_filter(&mut self, filter: Option<Query>) {
+        let prev_selected_idx = self.selected_msg_idx();
+        self.filter = filter;
+        self.update_view(prev_selected_idx);
+    }
+
+    // Rebuild the view from the filter, keeping `prev_selected_idx` selected if possible.
+    fn update_view(&mut self, prev_selected_idx: Option<usize>) {
+        self.view = self
+            .mbox
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, msg)| {
+                self.filter
+                    .as_ref()
+                    .is_none_or(|query| query.matches(msg))
+                    .then_some(idx)
+            })
+            .collect();
+
+        // Keep the same message selected if it's still visible, otherwise go back to the top.
+        match prev_selected_idx.and_then(|prev| self.view.iter().position(|idx| *idx == prev)) {
+            Some(view_idx) => self.selector.select(Some(view_idx)),
+            None => {
+                self.selector.select_first();
+                self.scroll_count = 0;
+                self.start_reading();
+            }
+        }
+    }
+
+    fn selected_msg_idx(&self) -> Option<usize> {
+        self.selector
+            .selected()
+            .and_then(|view_idx| self.view.get(view_idx).copied())
+    }
+
+    fn select(&mut self, view_idx: usize) {
+        self.selector.select(Some(view_idx));
         self.scroll_count = 0;
     }
 
+    fn select_offset(&mut self, offset: isize) {
+        let last_idx = self.view.len().saturating_sub(1);
+        let cur_idx = self.selector.selected().unwrap_or(0).min(last_idx);
+        self.select(cur_idx.saturating_add_signed(offset).min(last_idx));
+    }
+
+    // Scroll the body, stopping with the last line at the top.
+    fn scroll_by(&mut self, offset: isize) {
+        self.scroll_count = self
+            .scroll_count
+            .saturating_add_signed(offset)
+            .min(self.body_line_count.saturating_sub(1));
+        self.finish_reading();
+    }
+
+    // Mark the newly selected message as read, either now or once it has been open for long enough.
+    fn start_reading(&mut self) {
+        // Looking at any newly arrived message acknowledges the new mail.
+        if self
+            .selected_msg_idx()
+            .zip(self.new_mail_idx)
+            .is_some_and(|(sel_idx, new_idx)| sel_idx >= new_idx)
+        {
+            self.new_mail_idx = None;
+        }
+
+        self.read_pending = None;
+        if self.mark_read_delay.is_zero() {
+            self.set_selected_status(mbox::Status::Read);
+        } else if let Some(idx) = self.selected_msg_idx() {
+            self.read_pending = Some((idx, Instant::now()));
+        }
+    }
+
+    fn finish_reading(&mut self) {
+        if let Some((idx, _)) = self.read_pending.take() {
+            if let Some(msg) = self.mbox.msg_at_mut(idx) {
+                msg.set_status(mbox::Status::Read);
+            }
+        }
+    }
+
     fn set_selected_status(&mut self, status: mbox::Status) {
-        if let Some(idx) = self.selector.selected() {
+        if let Some(idx) = self.selected_msg_idx() {
             if let Some(msg) = self.mbox.msg_at_mut(idx) {
                 msg.set_status(status);
             }
         }
     }
 
+    fn pending_changes(&self) -> Changes {
+        let mut changes = Changes::default();
+        for (orig, msg) in self.orig_statuses.iter().zip(self.mbox.iter()) {
+            let was = |status| orig.contains(&status);
+            let is = |status| msg.has_status(status);
+
+            for (status, set_count, unset_count) in [
+                (
+                    mbox::Status::Deleted,
+                    &mut changes.deleted,
+                    &mut changes.undeleted,
+                ),
+                (
+                    mbox::Status::Read,
+                    &mut changes.marked_read,
+                    &mut changes.marked_unread,
+                ),
+                (
+                    mbox::Status::Flagged,
+                    &mut changes.flagged,
+                    &mut changes.unflagged,
+                ),
+            ] {
+                match (was(status), is(status)) {
+                    (false, true) => *set_count += 1,
+                    (true, false) => *unset_count += 1,
+                    _ => (),
+                }
+            }
+        }
+        changes
+    }
+
     fn render_selector_list(&mut self, area: Rect, buf: &mut Buffer) {
-        // A little util to remove the 'Title:' prefix.
-        let prepare_field = |line: &str| line.split(": ").nth(1).unwrap_or(line).to_string();
+        // Only the rows which fit are built, scrolled to keep the selected row in view the same way
+        // the table would.
+        let height = (area.height as usize).max(1);
+        let selected = self
+            .selector
+            .selected()
+            .map(|view_idx| view_idx.min(self.view.len().saturating_sub(1)));
+        let offset = match selected {
+            Some(view_idx) => self
+                .selector
+                .offset()
+                .min(view_idx)
+                .max((view_idx + 1).saturating_sub(height)),
+            None => 0,
+        };
 
-        let mut max_from_width = 0;
+        let (mbox, config, tagged) = (&self.mbox, &self.config, &self.tagged);
+        let row_cache = &mut self.row_cache;
+        row_cache.resize_with(mbox.count(), || None);
 
-        let rows = self
-            .mbox
+        // The From column is sized for every message, not just those in view, so it doesn't change
+        // width while scrolling.
+        let max_from_width = self
+            .view
             .iter()
+            .filter_map(|idx| mbox.msg_at(*idx))
             .map(|msg| {
-                let del_status = if msg.has_status(mbox::Status::Deleted) {
-                    "D"
-                } else {
-                    " "
-                };
-
-                let read_status = if msg.has_status(mbox::Status::Read) {
-                    " "
-                } else if msg.has_status(mbox::Status::NonRecent) {
-                    "U"
-                } else {
-                    "N"
-                };
-
-                let status = format!("{del_status}{read_status}");
-
-                let date = prepare_field(
-                    msg.field(mbox::FieldType::Date)
-                        .map(|line| {
-                            // Truncate the date string.  We're expecting it in the form
-                            // 'Fri, 4 Sep 2020 11:44:49 +1000 (AEST)' and we'll just cut
-                            // off the TZ stuff.
-                            line.split(" +").next().unwrap_or(line)
-                        })
-                        .unwrap_or("???"),
-                );
+                msg.field_value(mbox::FieldType::From)
+                    .unwrap_or("???")
+                    .len()
+            })
+            .max()
+            .unwrap_or(0);
 
-                let from = prepare_field(msg.field(mbox::FieldType::From).unwrap_or("???"));
-                max_from_width = max_from_width.max(from.len());
+        let rows = self
+            .view
+            .iter()
+            .skip(offset)
+            .take(height)
+            .filter_map(|idx| mbox.msg_at(*idx).map(|msg| (idx, msg)))
+            .map(|(idx, msg)| {
+                let tag_status = if tagged.contains(idx) { '*' } else { ' ' };
+                let status = format!("{tag_status}{}", msg.status_summary());
 
-                let subject = prepare_field(msg.field(mbox::FieldType::Subject).unwrap_or("???"));
+                let msg_statuses = statuses(msg);
+                let cached = match &mut row_cache[*idx] {
+                    Some(cached) if cached.statuses == msg_statuses => cached,
+                    entry => entry.insert(row_cache_entry(config, msg, msg_statuses)),
+                };
 
-                Row::new(vec![status, date, from, subject])
+                let row = Row::new(vec![
+                    Cell::from(status),
+                    Cell::from(cached.date.clone()),
+                    Cell::from(cached.from.clone()),
+                    Cell::from(cached.subject.clone()),
+                ]);
+                match cached.style {
+                    Some(row_style) => row.style(row_style),
+                    None => row,
+                }
             })
             .collect::<Vec<_>>();
 
         let widths = [
-            Constraint::Length(2),                     // Status is always 2.
+            Constraint::Length(4),                     // Status is always 4.
             Constraint::Length(25),                    // Date is always 25.
             Constraint::Length(max_from_width as u16), // Shrink to fit From.
             Constraint::Fill(1),                       // Subject gets the rest.
@@ -194,79 +1062,129 @@ impl IfaceState {
             .block(Block::new())
             .row_highlight_style(Style::default().fg(Color::Black).bg(Color::DarkGray));
 
-        StatefulWidget::render(table, area, buf, &mut self.selector);
+        let mut visible_state =
+            TableState::default().with_selected(selected.map(|view_idx| view_idx - offset));
+        StatefulWidget::render(table, area, buf, &mut visible_state);
+
+        self.selector
+            .select(visible_state.selected().map(|view_idx| view_idx + offset));
+        *self.selector.offset_mut() = offset;
     }
 
     fn render_body_text(&mut self, area: Rect, buf: &mut Buffer) {
         let selected_idx = self.selector.selected();
 
-        let title = if let Some(idx) = selected_idx {
-            format!("{}/{}", idx + 1, self.mbox.count())
+        let mut title = if let Some(idx) = selected_idx {
+            format!("{}/{}", (idx + 1).min(self.view.len()), self.view.len())
         } else {
-            format!("?/{}", self.mbox.count())
+            format!("?/{}", self.view.len())
         };
+        if let Some(query) = &self.filter {
+            title.push_str(&format!(" of {} [{query}]", self.mbox.count()));
+        }
 
         // XXX: There's a lot of copying going on here.  Ideally we'd be returning `&str` from the
         // mbox and highlighter and using the mbox lifetime everywhere.
-        let mut highlighter = self.highlight_config.highlighter();
-        let highlight_lines = |lines: &[String]| {
+        let highlights = &self.config.highlights;
+        let wrap = self.wrap;
+        let body_width = area.width.saturating_sub(2) as usize;
+        let highlight_lines = |msg: &mbox::Message, lines: &[String]| {
+            let mut highlighter = highlights.highlighter(msg);
             lines
                 .iter()
                 .map(|line| {
-                    let highlights = highlighter.next_highlights(line);
-                    if highlights.is_empty() {
-                        Line::from(line.clone())
-                    } else {
-                        let mut spans = Vec::default();
-
-                        let first_highlight_begin = highlights[0].begin;
-                        if first_highlight_begin > 0 {
-                            // Start of line is not highlighted.
-                            spans.push(Span::raw(line[0..first_highlight_begin].to_string()));
-                        }
-
-                        for Highlight { begin, end, colour } in &highlights {
-                            spans.push(Span::styled(
-                                line[*begin..*end].to_string(),
-                                Color::Indexed(*colour),
-                            ));
-                        }
+                    let line_highlights = highlighter.next_highlights(line);
+                    let mut styled_line = highlighted_line(line, &line_highlights);
 
-                        let last_highlight_end = highlights.last().unwrap().end;
-                        if last_highlight_end < line.len() {
-                            // End of line is not highlighted.
-                            spans.push(Span::raw(line[last_highlight_end..].to_string()));
-                        }
-
-                        Line::from(spans)
+                    // Fill the rest of a styled line with its style too.  Wrapped lines are only
+                    // styled as far as their text.
+                    if line_highlights.style.is_some() && !wrap {
+                        let fill_width = body_width.saturating_sub(styled_line.width());
+                        styled_line.push_span(Span::raw(" ".repeat(fill_width)));
                     }
+                    styled_line
                 })
                 .collect::<Vec<Line>>()
         };
 
+        // The selector may be temporarily past the end of the view until it is next rendered.
+        let selected_msg = self
+            .view
+            .get(
+                selected_idx
+                    .unwrap_or(0)
+                    .min(self.view.len().saturating_sub(1)),
+            )
+            .and_then(|idx| self.mbox.msg_at(*idx).map(|msg| (*idx, msg)));
+
+        let is_cached = match (&self.body_cache, selected_msg) {
+            (Some(cache), Some((msg_idx, msg))) => {
+                cache.msg_idx == msg_idx
+                    && cache.wrap == wrap
+                    && cache.body_width == body_width
+                    && cache.status_summary == msg.status_summary()
+            }
+            _ => false,
+        };
+        if !is_cached {
+            self.body_cache = selected_msg.map(|(msg_idx, msg)| BodyCache {
+                msg_idx,
+                status_summary: msg.status_summary(),
+                wrap,
+                body_width,
+                lines: msg
+                    .body_lines()
+                    .map(|lines| highlight_lines(msg, lines))
+                    .unwrap_or_default(),
+            });
+        }
         let message_lines = self
-            .mbox
-            .msg_at(selected_idx.unwrap_or(0))
-            .and_then(|msg| msg.body_lines())
-            .map(highlight_lines)
+            .body_cache
+            .as_ref()
+            .map(|cache| cache.lines.as_slice())
             .unwrap_or_default();
 
-        self.scrollbar = self
-            .scrollbar
-            .content_length(message_lines.len())
-            .position(self.scroll_count);
+        self.body_height = area.height.saturating_sub(2) as usize;
 
-        let body = Paragraph::new(message_lines)
-            .block(Block::bordered().title(Line::styled(title, Style::new().fg(Color::Green))))
-            .scroll((self.scroll_count as u16, 0));
+        // Only the visible lines are needed when they aren't wrapped.  Otherwise how the earlier
+        // lines wrap decides where the scrolled to line is.
+        let (visible_lines, scroll) = if self.wrap {
+            (message_lines.to_vec(), self.scroll_count)
+        } else {
+            (
+                message_lines
+                    .iter()
+                    .skip(self.scroll_count)
+                    .take(self.body_height)
+                    .cloned()
+                    .collect(),
+                0,
+            )
+        };
 
-        let wrapped_body = if self.wrap {
+        let body = Paragraph::new(visible_lines);
+        let body = if self.wrap {
             body.wrap(Wrap { trim: false })
         } else {
             body
         };
 
-        Widget::render(wrapped_body, area, buf);
+        // Scrolling is by wrapped rows when wrapping, so they're what's counted.
+        self.body_line_count = if self.wrap {
+            body.line_count(body_width as u16)
+        } else {
+            message_lines.len()
+        };
+        self.scrollbar = self
+            .scrollbar
+            .content_length(self.body_line_count)
+            .position(self.scroll_count);
+
+        let body = body
+            .block(Block::bordered().title(Line::styled(title, Style::new().fg(Color::Green))))
+            .scroll((scroll as u16, 0));
+
+        Widget::render(body, area, buf);
 
         StatefulWidget::render(
             Scrollbar::new(ScrollbarOrientation::VerticalRight)
@@ -282,18 +1200,310 @@ impl IfaceState {
             &mut self.scrollbar,
         );
     }
+
+    fn render_status_bar(&mut self, area: Rect, buf: &mut Buffer) {
+        let count = |status| {
+            self.mbox
+                .iter()
+                .filter(|msg| msg.has_status(status))
+                .count()
+        };
+        let unread_count = self.mbox.count() - count(mbox::Status::Read);
+        let new_count = self
+            .mbox
+            .iter()
+            .filter(|msg| {
+                !msg.has_status(mbox::Status::Read) && !msg.has_status(mbox::Status::NonRecent)
+            })
+            .count();
+
+        let mut status = format!(
+            " {}  {} messages, {unread_count} unread, {new_count} new, {} deleted, {} flagged",
+            self.mbox_path,
+            self.mbox.count(),
+            count(mbox::Status::Deleted),
+            count(mbox::Status::Flagged),
+        );
+        if !self.tagged.is_empty() {
+            status.push_str(&format!(", {} tagged", self.tagged.len()));
+        }
+        if let Some(query) = &self.filter {
+            status.push_str(&format!("  filter: {query}"));
+        }
+
+        let mut spans = vec![Span::raw(status)];
+        if self.new_mail_idx.is_some() {
+            spans.push(Span::styled(
+                "  [new mail]",
+                Style::new().fg(Color::Green).bold(),
+            ));
+        }
+        if self.read_only {
+            spans.push(Span::styled("  [read-only]", Style::new().fg(Color::Red)));
+        }
+        if !self.pending_changes().is_empty() {
+            spans.push(Span::styled("  [modified]", Style::new().fg(Color::Yellow)));
+        }
+
+        Widget::render(
+            Line::from(spans).style(Style::new().bg(Color::DarkGray).fg(Color::White)),
+            area,
+            buf,
+        );
+    }
+
+    // A line for each action with the keys bound to it and a description.
+    fn help_lines(&self) -> Vec<Line<'static>> {
+        let rows = Action::all()
+            .map(|action| (self.config.keys.keys_for(action).join(", "), action))
+            .collect::<Vec<_>>();
+        let keys_width = rows.iter().map(|(keys, _)| keys.len()).max().unwrap_or(0);
+        let name_width = Action::all()
+            .map(|action| action.name().len())
+            .max()
+            .unwrap_or(0);
+
+        rows.into_iter()
+            .map(|(keys, action)| {
+                Line::from(vec![
+                    Span::styled(format!("{keys:<keys_width$}  "), Color::Yellow),
+                    Span::styled(format!("{:<name_width$}  ", action.name()), Color::Green),
+                    Span::raw(action.description()),
+                ])
+            })
+            .collect()
+    }
+
+    fn render_help(&mut self, area: Rect, buf: &mut Buffer) {
+        let lines = self.help_lines();
+        let popup_area = popup_area(area, &lines);
+        let height = popup_area.height;
+
+        let scroll = self.help_scroll.unwrap_or(0).min(
+            lines
+                .len()
+                .saturating_sub(height.saturating_sub(2) as usize),
+        );
+        self.help_scroll = Some(scroll);
+
+        let help = Paragraph::new(lines)
+            .block(
+                Block::bordered()
+                    .title(Line::styled(" Keys ", Style::new().fg(Color::Green)))
+                    .padding(Padding::horizontal(1)),
+            )
+            .scroll((scroll as u16, 0));
+
+        Widget::render(Clear, popup_area, buf);
+        Widget::render(help, popup_area, buf);
+    }
+
+    fn render_confirm(&mut self, exit_type: ExitType, area: Rect, buf: &mut Buffer) {
+        let (title, question) = match exit_type {
+            ExitType::Update => (" Quit ", "Save these changes and quit?"),
+            ExitType::NoChange => (" Exit ", "Discard these changes and quit?"),
+        };
+        let key = |key: &'static str| Span::styled(key, Style::new().reversed());
+
+        let lines = vec![
+            Line::raw(format!("{}.", self.pending_changes())),
+            Line::raw(""),
+            Line::raw(question),
+            Line::raw(""),
+            Line::from(vec![
+                key("y"),
+                Span::raw(" yes  "),
+                key("r"),
+                Span::raw(" review deleted  "),
+                key("n"),
+                Span::raw(" cancel"),
+            ]),
+        ];
+
+        let popup_area = popup_area(area, &lines);
+        let confirm = Paragraph::new(lines).block(
+            Block::bordered()
+                .title(Line::styled(title, Style::new().fg(Color::Green)))
+                .padding(Padding::horizontal(1)),
+        );
+
+        Widget::render(Clear, popup_area, buf);
+        Widget::render(confirm, popup_area, buf);
+    }
+
+    // A reminder of the most useful keys, shown at the bottom when there's nothing else to show.
+    fn hint_line(&self) -> Line<'static> {
+        let hints = [
+            (Action::Help, "help"),
+            (Action::Quit, "quit"),
+            (Action::Exit, "exit"),
+            (Action::NextMessage, "next"),
+            (Action::PrevMessage, "prev"),
+            (Action::Delete, "delete"),
+            (Action::ToggleTag, "tag"),
+            (Action::Search, "search"),
+        ];
+
+        let mut spans = Vec::default();
+        for (action, label) in hints {
+            if let Some(key) = self.config.keys.keys_for(action).first() {
+                spans.push(Span::styled(key.clone(), Style::new().reversed()));
+                spans.push(Span::raw(format!(" {label}  ")));
+            }
+        }
+
+        Line::from(spans).style(Style::new().fg(Color::DarkGray))
+    }
+
+    fn render_prompt(&mut self, area: Rect, buf: &mut Buffer) {
+        if self.prompt.is_none() && self.notice.is_none() {
+            Widget::render(self.hint_line(), area, buf);
+            return;
+        }
+
+        let mut spans = Vec::default();
+        if let Some(Prompt { kind, text }) = &self.prompt {
+            spans.push(Span::raw(format!("{}{text}", kind.label())));
+            spans.push(Span::styled(" ", Style::new().reversed()));
+            spans.push(Span::raw("  "));
+        }
+        match &self.notice {
+            Some(Notice::Info(notice)) => spans.push(Span::raw(notice.clone())),
+            Some(Notice::Error(notice)) => {
+                spans.push(Span::styled(notice.clone(), Style::new().fg(Color::Red)))
+            }
+            None => (),
+        }
+
+        Widget::render(Line::from(spans), area, buf);
+    }
 }
 
 impl Widget for &mut IfaceState {
     fn render(self, area: Rect, buf: &mut Buffer) {
-        // Render the list with an entry for every message, but at most 10 entries.
-        let [list_area, text_area] = Layout::vertical([
-            Constraint::Length(self.mbox.count().min(10) as u16),
+        // Render the list with an entry for every visible message, but at most 10 entries.  The
+        // bottom line shows the prompt or a notice, or otherwise some key hints.
+        let [list_area, text_area, status_area, prompt_area] = Layout::vertical([
+            Constraint::Length(self.view.len().min(10) as u16),
             Constraint::Min(1),
+            Constraint::Length(1),
+            Constraint::Length(1),
         ])
         .areas(area);
 
         self.render_selector_list(list_area, buf);
         self.render_body_text(text_area, buf);
+        self.render_status_bar(status_area, buf);
+        self.render_prompt(prompt_area, buf);
+
+        if self.help_scroll.is_some() {
+            self.render_help(area, buf);
+        }
+        if let Some(exit_type) = self.confirm_exit {
+            self.render_confirm(exit_type, area, buf);
+        }
+    }
+}
+
+// Centre a bordered popup in `area`, shrinking it to fit `lines` where possible.
+fn popup_area(area: Rect, lines: &[Line]) -> Rect {
+    let width = lines
+        .iter()
+        .map(|line| line.width() as u16 + 4)
+        .max()
+        .unwrap_or(0)
+        .min(area.width);
+    let height = (lines.len() as u16 + 2).min(area.height);
+
+    Rect {
+        x: area.x + (area.width - width) / 2,
+        y: area.y + (area.height - height) / 2,
+        width,
+        height,
+    }
+}
+
+// Highlight the header values shown in the message list for a message, and find its row style.
+fn row_cache_entry(config: &Config, msg: &mbox::Message, statuses: Vec<mbox::Status>) -> RowCache {
+    let header_line = |header: &str, value: &str| {
+        highlighted_line(
+            value,
+            &highlight::header_highlights(&config.header_highlights, msg, header, value),
+        )
+    };
+
+    let date = msg
+        .field_value(mbox::FieldType::Date)
+        .map(|value| {
+            // Truncate the date string.  We're expecting it in the form
+            // 'Fri, 4 Sep 2020 11:44:49 +1000 (AEST)' and we'll just cut off the TZ stuff.
+            value.split(" +").next().unwrap_or(value)
+        })
+        .unwrap_or("???");
+    let from = msg.field_value(mbox::FieldType::From).unwrap_or("???");
+    let subject = msg.field_value(mbox::FieldType::Subject).unwrap_or("???");
+
+    RowCache {
+        statuses,
+        style: highlight::row_style(&config.row_highlights, msg).map(|style| ratatui_style(&style)),
+        date: header_line("date", date),
+        from: header_line("from", from),
+        subject: header_line("subject", subject),
+    }
+}
+
+// Convert a line of text and its highlights into a styled line.
+fn highlighted_line(line: &str, highlights: &LineHighlights) -> Line<'static> {
+    // The highlights are in order and don't overlap, so fill in the gaps between them with the
+    // unhighlighted text.
+    let mut line_spans = Vec::default();
+    let mut pos = 0;
+    for Highlight {
+        begin, end, style, ..
+    } in &highlights.spans
+    {
+        if *begin > pos {
+            line_spans.push(Span::raw(line[pos..*begin].to_string()));
+        }
+        line_spans.push(Span::styled(
+            line[*begin..*end].to_string(),
+            ratatui_style(style),
+        ));
+        pos = *end;
+    }
+    if pos < line.len() || line_spans.is_empty() {
+        line_spans.push(Span::raw(line[pos..].to_string()));
+    }
+
+    match &highlights.style {
+        Some(line_style) => Line::from(line_spans).style(ratatui_style(line_style)),
+        None => Line::from(line_spans),
+    }
+}
+
+fn ratatui_style(style: &HighlightStyle) -> Style {
+    let colour = |colour| match colour {
+        Colour::Unset => None,
+        Colour::Indexed(idx) => Some(Color::Indexed(idx)),
+        Colour::Rgb(r, g, b) => Some(Color::Rgb(r, g, b)),
+    };
+
+    let mut modifiers = Modifier::empty();
+    for (enabled, modifier) in [
+        (style.bold, Modifier::BOLD),
+        (style.italic, Modifier::ITALIC),
+        (style.underline, Modifier::UNDERLINED),
+        (style.reverse, Modifier::REVERSED),
+    ] {
+        if enabled {
+            modifiers |= modifier;
+        }
+    }
+
+    Style {
+        fg: colour(style.fg),
+        bg: colour(style.bg),
+        add_modifier: modifiers,
+        ..Style::default()
     }
 }
@@ -0,0 +1,662 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use fxhash::FxHashMap;
+
+// -------------------------------------------------------------------------------------------------
+// Key bindings map sequences of key chords to named actions.  They're configured in the `keys`
+// section of the config file as a map from key sequence to action name, e.g.,
+//
+//   keys: {
+//       "ctrl-n": "next-message",
+//       "g g": "first-message",
+//       "x": "none",
+//   }
+//
+// A chord is a key name with optional `ctrl-`, `alt-` and `shift-` modifier prefixes, and a
+// sequence is one or more chords separated by whitespace.  Configured bindings are merged over the
+// defaults, replacing any default which would conflict with them, and binding to `none` removes a
+// default binding.
+
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub(crate) enum Action {
+    Quit,
+    Exit,
+    Sync,
+    NextMessage,
+    PrevMessage,
+    FirstMessage,
+    LastMessage,
+    PageDown,
+    PageUp,
+    HalfPageDown,
+    HalfPageUp,
+    LineDown,
+    LineUp,
+    BodyTop,
+    ToggleWrap,
+    Delete,
+    Undelete,
+    MarkRead,
+    MarkUnread,
+    ToggleRead,
+    ToggleFlagged,
+    DeleteVisible,
+    Save,
+    Pipe,
+    ToggleTag,
+    TagRange,
+    TagAllVisible,
+    TagMatching,
+    TagPrefix,
+    Search,
+    ClearFilter,
+    Help,
+}
+
+// Each action with its name in the config file and a description for the help overlay.
+const ACTIONS: &[(&str, Action, &str)] = &[
+    ("quit", Action::Quit, "Save changes and quit."),
+    ("exit", Action::Exit, "Quit without saving changes."),
+    (
+        "sync",
+        Action::Sync,
+        "Save changes now and reload the mailbox.",
+    ),
+    (
+        "next-message",
+        Action::NextMessage,
+        "Select the next message.",
+    ),
+    (
+        "prev-message",
+        Action::PrevMessage,
+        "Select the previous message.",
+    ),
+    (
+        "first-message",
+        Action::FirstMessage,
+        "Select the first message.",
+    ),
+    (
+        "last-message",
+        Action::LastMessage,
+        "Select the last message.",
+    ),
+    (
+        "page-down",
+        Action::PageDown,
+        "Scroll the message body down a page.",
+    ),
+    (
+        "page-up",
+        Action::PageUp,
+        "Scroll the message body up a page.",
+    ),
+    (
+        "half-page-down",
+        Action::HalfPageDown,
+        "Scroll the message body down half a page.",
+    ),
+    (
+        "half-page-up",
+        Action::HalfPageUp,
+        "Scroll the message body up half a page.",
+    ),
+    (
+        "line-down",
+        Action::LineDown,
+        "Scroll the message body down a line.",
+    ),
+    (
+        "line-up",
+        Action::LineUp,
+        "Scroll the message body up a line.",
+    ),
+    (
+        "body-top",
+        Action::BodyTop,
+        "Scroll back to the top of the message body.",
+    ),
+    ("toggle-wrap", Action::ToggleWrap, "Toggle line wrapping."),
+    (
+        "delete",
+        Action::Delete,
+        "Delete the message and select the next.",
+    ),
+    ("undelete", Action::Undelete, "Undelete the message."),
+    ("mark-read", Action::MarkRead, "Mark the message read."),
+    (
+        "mark-unread",
+        Action::MarkUnread,
+        "Mark the message unread.",
+    ),
+    ("toggle-read", Action::ToggleRead, "Toggle the read status."),
+    ("toggle-flagged", Action::ToggleFlagged, "Toggle the flag."),
+    (
+        "delete-visible",
+        Action::DeleteVisible,
+        "Delete every visible message.",
+    ),
+    ("save", Action::Save, "Append the message to a file."),
+    ("pipe", Action::Pipe, "Pipe the message to a shell command."),
+    (
+        "toggle-tag",
+        Action::ToggleTag,
+        "Toggle the tag and select the next message.",
+    ),
+    (
+        "tag-range",
+        Action::TagRange,
+        "Tag from the last tagged message to the selection.",
+    ),
+    (
+        "tag-all-visible",
+        Action::TagAllVisible,
+        "Tag (or untag) every visible message.",
+    ),
+    (
+        "tag-matching",
+        Action::TagMatching,
+        "Tag the visible messages matching a query.",
+    ),
+    (
+        "tag-prefix",
+        Action::TagPrefix,
+        "Apply the next action to the tagged messages.",
+    ),
+    (
+        "search",
+        Action::Search,
+        "Filter the message list with a query.",
+    ),
+    ("clear-filter", Action::ClearFilter, "Clear the filter."),
+    ("help", Action::Help, "Show this help."),
+];
+
+const DEFAULT_BINDINGS: &[(&str, Action)] = &[
+    ("q", Action::Quit),
+    ("x", Action::Exit),
+    ("$", Action::Sync),
+    ("j", Action::NextMessage),
+    ("down", Action::NextMessage),
+    ("k", Action::PrevMessage),
+    ("up", Action::PrevMessage),
+    ("g g", Action::FirstMessage),
+    ("home", Action::FirstMessage),
+    ("G", Action::LastMessage),
+    ("end", Action::LastMessage),
+    ("space", Action::PageDown),
+    ("pagedown", Action::PageDown),
+    ("b", Action::PageUp),
+    ("pageup", Action::PageUp),
+    ("ctrl-d", Action::HalfPageDown),
+    ("ctrl-u", Action::HalfPageUp),
+    ("J", Action::LineDown),
+    ("K", Action::LineUp),
+    ("0", Action::BodyTop),
+    ("s", Action::ToggleWrap),
+    ("d", Action::Delete),
+    ("u", Action::Undelete),
+    ("r", Action::MarkRead),
+    ("U", Action::MarkUnread),
+    ("N", Action::ToggleRead),
+    ("F", Action::ToggleFlagged),
+    ("D", Action::DeleteVisible),
+    ("w", Action::Save),
+    ("|", Action::Pipe),
+    ("t", Action::ToggleTag),
+    ("v", Action::TagRange),
+    ("*", Action::TagAllVisible),
+    ("T", Action::TagMatching),
+    (";", Action::TagPrefix),
+    ("/", Action::Search),
+    ("esc", Action::ClearFilter),
+    ("?", Action::Help),
+    ("f1", Action::Help),
+];
+
+impl Action {
+    pub(crate) fn name(&self) -> &'static str {
+        self.entry().0
+    }
+
+    pub(crate) fn description(&self) -> &'static str {
+        self.entry().2
+    }
+
+    /// Every action, in a sensible order for listing them.
+    pub(crate) fn all() -> impl Iterator<Item = Action> {
+        ACTIONS.iter().map(|(_, action, _)| *action)
+    }
+
+    fn entry(&self) -> &'static (&'static str, Action, &'static str) {
+        ACTIONS
+            .iter()
+            .find(|(_, action, _)| action == self)
+            .expect("BUG! Every action must be in ACTIONS.")
+    }
+}
+
+impl std::str::FromStr for Action {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        ACTIONS
+            .iter()
+            .find_map(|(action_name, action, _)| (*action_name == name).then_some(*action))
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Unknown action '{name}'; expecting one of: none, {}.",
+                    ACTIONS
+                        .iter()
+                        .map(|(action_name, _, _)| *action_name)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })
+    }
+}
+
+// -------------------------------------------------------------------------------------------------
+
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub(crate) struct KeyChord {
+    code: KeyCode,
+    modifiers: KeyModifiers,
+}
+
+const KEY_NAMES: &[(&str, KeyCode)] = &[
+    ("space", KeyCode::Char(' ')),
+    ("enter", KeyCode::Enter),
+    ("esc", KeyCode::Esc),
+    ("tab", KeyCode::Tab),
+    ("backtab", KeyCode::BackTab),
+    ("backspace", KeyCode::Backspace),
+    ("delete", KeyCode::Delete),
+    ("insert", KeyCode::Insert),
+    ("up", KeyCode::Up),
+    ("down", KeyCode::Down),
+    ("left", KeyCode::Left),
+    ("right", KeyCode::Right),
+    ("pageup", KeyCode::PageUp),
+    ("pagedown", KeyCode::PageDown),
+    ("home", KeyCode::Home),
+    ("end", KeyCode::End),
+];
+
+impl From<KeyEvent> for KeyChord {
+    fn from(key: KeyEvent) -> Self {
+        KeyChord::new(key.code, key.modifiers)
+    }
+}
+
+impl KeyChord {
+    fn new(code: KeyCode, modifiers: KeyModifiers) -> Self {
+        // Shifted characters are reported as the character itself, and shift-tab as backtab, so
+        // the shift is redundant.  A configured 'shift-tab' is the same as 'backtab'.
+        let (code, modifiers) = match code {
+            KeyCode::Char(_) | KeyCode::BackTab => (code, modifiers - KeyModifiers::SHIFT),
+            KeyCode::Tab if modifiers.contains(KeyModifiers::SHIFT) => {
+                (KeyCode::BackTab, modifiers - KeyModifiers::SHIFT)
+            }
+            _ => (code, modifiers),
+        };
+
+        KeyChord {
+            code,
+            modifiers: modifiers
+                & (KeyModifiers::CONTROL | KeyModifiers::ALT | KeyModifiers::SHIFT),
+        }
+    }
+}
+
+impl std::str::FromStr for KeyChord {
+    type Err = anyhow::Error;
+
+    fn from_str(chord_str: &str) -> Result<Self, Self::Err> {
+        let mut modifiers = KeyModifiers::NONE;
+        let mut key_str = chord_str;
+        // A lone '-' is a key, not a modifier separator.
+        while let Some((prefix, rest)) =
+            key_str.split_once('-').filter(|(_, rest)| !rest.is_empty())
+        {
+            modifiers |= match prefix.to_lowercase().as_str() {
+                "ctrl" => KeyModifiers::CONTROL,
+                "alt" => KeyModifiers::ALT,
+                "shift" => KeyModifiers::SHIFT,
+                _ => break,
+            };
+            key_str = rest;
+        }
+
+        let mut chars = key_str.chars();
+        let code = match (chars.next(), chars.next()) {
+            (Some(ch), None) if modifiers.contains(KeyModifiers::SHIFT) => {
+                KeyCode::Char(ch.to_ascii_uppercase())
+            }
+            (Some(ch), None) => KeyCode::Char(ch),
+            _ => {
+                let key_name = key_str.to_lowercase();
+                match KEY_NAMES
+                    .iter()
+                    .find_map(|(name, code)| (*name == key_name).then_some(*code))
+                {
+                    Some(code) => code,
+                    None => match key_name.strip_prefix('f').and_then(|n| n.parse().ok()) {
+                        Some(n) if (1..=12).contains(&n) => KeyCode::F(n),
+                        _ => anyhow::bail!(
+                            "Unknown key '{key_str}' in '{chord_str}'; expecting a single \
+                            character, f1 to f12 or one of: {}.",
+                            KEY_NAMES
+                                .iter()
+                                .map(|(name, _)| *name)
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        ),
+                    },
+                }
+            }
+        };
+
+        Ok(KeyChord::new(code, modifiers))
+    }
+}
+
+impl std::fmt::Display for KeyChord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (modifier, prefix) in [
+            (KeyModifiers::CONTROL, "ctrl-"),
+            (KeyModifiers::ALT, "alt-"),
+            (KeyModifiers::SHIFT, "shift-"),
+        ] {
+            if self.modifiers.contains(modifier) {
+                f.write_str(prefix)?;
+            }
+        }
+
+        match self.code {
+            KeyCode::F(n) => write!(f, "f{n}"),
+            code => match KEY_NAMES
+                .iter()
+                .find_map(|(name, key_code)| (*key_code == code).then_some(*name))
+            {
+                Some(name) => f.write_str(name),
+                None => match code {
+                    KeyCode::Char(ch) => write!(f, "{ch}"),
+                    code => write!(f, "{code:?}"),
+                },
+            },
+        }
+    }
+}
+
+fn parse_sequence(seq_str: &str) -> anyhow::Result<Vec<KeyChord>> {
+    let chords = seq_str
+        .split_whitespace()
+        .map(|chord_str| chord_str.parse())
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    if chords.is_empty() {
+        anyhow::bail!("Empty key sequence.");
+    }
+
+    Ok(chords)
+}
+
+fn sequence_to_string(seq: &[KeyChord]) -> String {
+    seq.iter()
+        .map(|chord| chord.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// -------------------------------------------------------------------------------------------------
+
+#[derive(Debug)]
+pub(crate) struct Keymap {
+    bindings: FxHashMap<Vec<KeyChord>, Action>,
+}
+
+pub(crate) enum Lookup {
+    Action(Action),
+    Prefix,
+    Unbound,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Keymap {
+            bindings: DEFAULT_BINDINGS
+                .iter()
+                .map(|(seq_str, action)| {
+                    (
+                        parse_sequence(seq_str).expect("BUG! Default key bindings must be valid."),
+                        *action,
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Keymap {
+    /// Find the action for a sequence of keys, or whether it is the start of a longer sequence.
+    pub(crate) fn lookup(&self, keys: &[KeyChord]) -> Lookup {
+        match self.bindings.get(keys) {
+            Some(action) => Lookup::Action(*action),
+            None if self
+                .bindings
+                .keys()
+                .any(|seq| seq.len() > keys.len() && seq.starts_with(keys)) =>
+            {
+                Lookup::Prefix
+            }
+            None => Lookup::Unbound,
+        }
+    }
+
+    /// The key sequences bound to an action, shortest first.
+    pub(crate) fn keys_for(&self, action: Action) -> Vec<String> {
+        let mut seqs = self
+            .bindings
+            .iter()
+            .filter_map(|(seq, bound_action)| (*bound_action == action).then_some(seq))
+            .collect::<Vec<_>>();
+        seqs.sort_by_key(|seq| {
+            let seq_str = sequence_to_string(seq);
+            (seq.len(), seq_str.len(), seq_str)
+        });
+
+        seqs.into_iter()
+            .map(|seq| sequence_to_string(seq))
+            .collect()
+    }
+
+    fn bind(&mut self, seq: Vec<KeyChord>, action: Option<Action>) {
+        // Remove any binding which is a prefix of the new one or which the new one is a prefix
+        // of, otherwise they couldn't be told apart.
+        self.bindings
+            .retain(|bound_seq, _| !bound_seq.starts_with(&seq) && !seq.starts_with(bound_seq));
+
+        if let Some(action) = action {
+            self.bindings.insert(seq, action);
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Keymap {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let config_bindings = FxHashMap::<String, String>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(seq_str, action_name)| {
+                parse_sequence(&seq_str).map(|seq| (seq_str, seq, action_name))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map_err(serde::de::Error::custom)?;
+
+        // Configured bindings may replace conflicting defaults, but not each other.
+        for (idx, (seq_str_a, seq_a, _)) in config_bindings.iter().enumerate() {
+            for (seq_str_b, seq_b, _) in &config_bindings[idx + 1..] {
+                if seq_a.starts_with(seq_b) || seq_b.starts_with(seq_a) {
+                    return Err(serde::de::Error::custom(format!(
+                        "Key sequences '{seq_str_a}' and '{seq_str_b}' conflict; one is the \
+                        start of the other."
+                    )));
+                }
+            }
+        }
+
+        let mut keymap = Keymap::default();
+        for (seq_str, seq, action_name) in config_bindings {
+            let action = match action_name.as_str() {
+                "none" => None,
+                name => Some(
+                    name.parse::<Action>()
+                        .map_err(|err| format!("Bad binding for '{seq_str}': {err}"))
+                        .map_err(serde::de::Error::custom)?,
+                ),
+            };
+            keymap.bind(seq, action);
+        }
+
+        Ok(keymap)
+    }
+}
+
+impl serde::Serialize for Keymap {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(
+            self.bindings
+                .iter()
+                .map(|(seq, action)| (sequence_to_string(seq), action.name())),
+        )
+    }
+}
+
+// -------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chord(chord_str: &str) -> KeyChord {
+        chord_str
+            .parse()
+            .unwrap_or_else(|err| panic!("Failed to parse '{}': {}", chord_str, err))
+    }
+
+    fn event(code: KeyCode, modifiers: KeyModifiers) -> KeyChord {
+        KeyChord::from(KeyEvent::new(code, modifiers))
+    }
+
+    fn keymap(keys_str: &str) -> anyhow::Result<Keymap> {
+        Ok(ron::from_str(keys_str)?)
+    }
+
+    fn keymap_err(keys_str: &str) -> String {
+        keymap(keys_str)
+            .err()
+            .unwrap_or_else(|| panic!("Expected '{}' to fail to parse.", keys_str))
+            .to_string()
+    }
+
+    fn lookup_action(keymap: &Keymap, keys: &[KeyChord]) -> Option<Action> {
+        match keymap.lookup(keys) {
+            Lookup::Action(action) => Some(action),
+            Lookup::Prefix | Lookup::Unbound => None,
+        }
+    }
+
+    #[test]
+    fn shifted_characters() {
+        // Terminals report shifted characters as the character itself, with or without shift.
+        assert_eq!(chord("G"), event(KeyCode::Char('G'), KeyModifiers::SHIFT));
+        assert_eq!(chord("G"), event(KeyCode::Char('G'), KeyModifiers::NONE));
+        assert_eq!(chord("shift-a"), chord("A"));
+        assert_eq!(chord("shift-G"), chord("G"));
+        assert_eq!(
+            chord("ctrl-shift-a"),
+            event(
+                KeyCode::Char('A'),
+                KeyModifiers::CONTROL | KeyModifiers::SHIFT
+            )
+        );
+        assert_ne!(chord("a"), chord("A"));
+    }
+
+    #[test]
+    fn backtab() {
+        // Crossterm reports shift-tab as backtab with shift.
+        assert_eq!(
+            chord("backtab"),
+            event(KeyCode::BackTab, KeyModifiers::SHIFT)
+        );
+        assert_eq!(chord("backtab"), event(KeyCode::Tab, KeyModifiers::SHIFT));
+        assert_eq!(chord("shift-tab"), chord("backtab"));
+        assert_ne!(chord("tab"), chord("backtab"));
+
+        let keymap = keymap(r#"{"backtab": "help"}"#).unwrap();
+        assert_eq!(
+            lookup_action(&keymap, &[event(KeyCode::BackTab, KeyModifiers::SHIFT)]),
+            Some(Action::Help)
+        );
+    }
+
+    #[test]
+    fn modifiers_and_dashes() {
+        assert_eq!(chord("-"), event(KeyCode::Char('-'), KeyModifiers::NONE));
+        assert_eq!(
+            chord("ctrl--"),
+            event(KeyCode::Char('-'), KeyModifiers::CONTROL)
+        );
+        assert_eq!(
+            chord("Ctrl-Alt-x"),
+            event(
+                KeyCode::Char('x'),
+                KeyModifiers::CONTROL | KeyModifiers::ALT
+            )
+        );
+        assert_eq!(
+            chord("ctrl-f5"),
+            event(KeyCode::F(5), KeyModifiers::CONTROL)
+        );
+        assert_eq!(chord("ctrl--").to_string(), "ctrl--");
+        assert_eq!(chord("shift-tab").to_string(), "backtab");
+    }
+
+    #[test]
+    fn unknown_keys_and_actions() {
+        let err = "ctrl-foo".parse::<KeyChord>().unwrap_err().to_string();
+        assert!(
+            err.starts_with("Unknown key 'foo' in 'ctrl-foo'; expecting a single character"),
+            "{}",
+            err
+        );
+        assert!("f13".parse::<KeyChord>().is_err());
+        assert!(parse_sequence(" ").is_err());
+
+        let err = keymap_err(r#"{"z": "fly"}"#);
+        assert!(
+            err.contains("Bad binding for 'z': Unknown action 'fly'; expecting one of: none, "),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn configured_bindings() {
+        // Configured bindings replace any conflicting defaults, or remove them with 'none'.
+        let keymap = keymap(r#"{"g": "help", "G": "none"}"#).unwrap();
+        assert_eq!(lookup_action(&keymap, &[chord("g")]), Some(Action::Help));
+        assert_eq!(lookup_action(&keymap, &[chord("G")]), None);
+        assert_eq!(lookup_action(&keymap, &[chord("q")]), Some(Action::Quit));
+        assert_eq!(lookup_action(&keymap, &[chord("g"), chord("g")]), None);
+
+        // But they can't conflict with each other.
+        let err = keymap_err(r#"{"g": "help", "g g": "quit"}"#);
+        assert!(
+            err.contains("conflict; one is the start of the other."),
+            "{}",
+            err
+        );
+    }
+}
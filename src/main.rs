@@ -1,42 +1,85 @@
-use std::{
-    fs,
-    io::{BufRead, BufReader, Write},
-    iter::FromIterator,
-    os::unix::fs::PermissionsExt,
-};
+use std::{fs, io, iter::FromIterator, process::ExitCode};
 
 use anyhow::Context;
 
+mod config;
 mod highlight;
+mod hook;
 mod iface;
+mod keymap;
 mod mbox;
+mod query;
+mod watch;
 
 // -------------------------------------------------------------------------------------------------
 
-fn main() -> anyhow::Result<()> {
-    let mbox_path = mbox::get_mbox_path()?;
-    let mbox_file = fs::File::open(&mbox_path)
-        .with_context(|| format!("Failed to open mbox file '{mbox_path}'."))?;
-    let mbox_mtime = mbox_file.metadata()?.modified()?;
+const USAGE: &str = "\
+Usage: smbox [COMMAND]
+
+Commands:
+  (none)          Read the mail in $MAIL interactively.
+  search QUERY    Print a summary of each message matching QUERY.
+  watch [OPTIONS] [QUERY]
+                  Print a summary of each new message as it is delivered, if it
+                  matches QUERY.
+    -f, --full        Print the whole message, highlighted, instead.
+    -e, --exec CMD    Run shell command CMD for each message, with the message on
+                      stdin.
+  status [-q]     Print the number of new and unread messages, plus any configured
+                  counts, exiting with failure if there is no new mail.
+    -q, --quiet       Print nothing, only exit.
+  count [-q]      The same as status.
+  highlight-test [RULES] INPUT
+                  Print INPUT highlighted, with a trace of the contexts entered
+                  and exited and the rule matched for each highlight.  RULES is a
+                  config file to use instead of the usual one.  INPUT is a text or
+                  mbox file, '-' for stdin or the number of a message in $MAIL.
+  help            Print this message.";
+
+fn main() -> anyhow::Result<ExitCode> {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+
+    match args.split_first() {
+        None => read_mail().map(|_| ExitCode::SUCCESS),
+        Some((cmd, args)) if cmd == "search" => search(args),
+        Some((cmd, args)) if cmd == "watch" => watch(args),
+        Some((cmd, args)) if cmd == "status" || cmd == "count" => status(args),
+        Some((cmd, args)) if cmd == "highlight-test" => highlight_test(args),
+        Some((cmd, _)) if cmd == "help" || cmd == "-h" || cmd == "--help" => {
+            println!("{USAGE}");
+            Ok(ExitCode::SUCCESS)
+        }
+        Some((cmd, _)) => anyhow::bail!("Unknown command '{cmd}'.\n\n{USAGE}"),
+    }
+}
 
-    let lines = BufReader::new(mbox_file)
-        .lines()
-        .collect::<Result<Vec<_>, _>>()?;
+// -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -
+
+fn read_mail() -> anyhow::Result<()> {
+    let (lines, mbox_mtime, mbox_len) = mbox::read_mbox_lines(0)?;
 
     if lines.is_empty() {
         println!("No mail.");
     } else {
         let messages = mbox::Mbox::from_iter(lines);
-        let highlighter = build_highlighter_from_config()?;
+        let config = config::load()?;
 
-        if let Some(mut updated_messages) = iface::run(messages, highlighter)? {
-            for msg in updated_messages.iter_mut() {
-                msg.set_status(mbox::Status::NonRecent);
-            }
+        // We can't save any changes if the mailbox can't be written.  Opening it to append won't
+        // modify it.
+        let mbox_path = mbox::get_mbox_path()?;
+        let read_only = fs::OpenOptions::new()
+            .append(true)
+            .open(&mbox_path)
+            .is_err();
+
+        if let Some((mut updated_messages, mbox_mtime)) =
+            iface::run(messages, config, mbox_path, mbox_mtime, mbox_len, read_only)?
+        {
+            updated_messages.mark_all_seen();
 
             println!(
                 "{}",
-                match write_mbox(&updated_messages, mbox_mtime)? {
+                match mbox::write_mbox(&updated_messages, mbox_mtime)? {
                     n if n == updated_messages.count() as i64 => "Deleted all messages.".to_owned(),
                     1 => "Deleted 1 message.".to_owned(),
                     n => format!("Deleted {n} messages."),
@@ -50,64 +93,260 @@ fn main() -> anyhow::Result<()> {
 
 // -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -
 
-fn build_highlighter_from_config() -> anyhow::Result<highlight::HighlightConfig> {
-    let base_dirs =
-        directories::BaseDirs::new().context("Failed to determine config file path.")?;
+// Print a one line summary of each matching message, exiting with failure if there are none, like
+// grep.  The query may be split across multiple arguments for convenience.
+fn search(args: &[String]) -> anyhow::Result<ExitCode> {
+    let query = query::Query::parse(&args.join(" "))?;
+    let (lines, _, _) = mbox::read_mbox_lines(0)?;
 
-    let mut config_file_path = base_dirs.config_dir().to_owned();
-    config_file_path.push("smbox.ron");
+    let mut num_matches = 0;
+    for (idx, msg) in mbox::Mbox::from_iter(lines).iter().enumerate() {
+        if query.matches(msg) {
+            println!("{:>4} {}", idx + 1, message_summary(msg));
+            num_matches += 1;
+        }
+    }
+
+    Ok(if num_matches > 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
 
-    Ok(if fs::exists(&config_file_path)? {
-        ron::from_str(&fs::read_to_string(&config_file_path).with_context(|| {
-            format!(
-                "Failed to read from config file at {}.",
-                config_file_path.display()
+// Print a compact summary for shell prompts and status lines, e.g., '3 new, 5 unread, 1 FAILED',
+// exiting with success only if there is new mail.
+fn status(args: &[String]) -> anyhow::Result<ExitCode> {
+    let mut quiet = false;
+    for arg in args {
+        match arg.as_str() {
+            "-q" | "--quiet" => quiet = true,
+            _ => anyhow::bail!("Unknown argument '{arg}' for status.\n\n{USAGE}"),
+        }
+    }
+
+    let config = config::load()?;
+    let with_bodies = config
+        .status_counts
+        .iter()
+        .any(|count| count.query.uses_body());
+    let mbox = mbox::scan_mbox(with_bodies)?;
+
+    let count_matching = |pred: &dyn Fn(&mbox::Message) -> bool| {
+        mbox.iter()
+            .filter(|msg| !msg.has_status(mbox::Status::Deleted) && pred(msg))
+            .count()
+    };
+    let new_count = count_matching(&|msg| {
+        !msg.has_status(mbox::Status::Read) && !msg.has_status(mbox::Status::NonRecent)
+    });
+    let unread_count = count_matching(&|msg| !msg.has_status(mbox::Status::Read));
+
+    if !quiet {
+        let mut counts = vec![
+            (new_count, "new".to_owned()),
+            (unread_count, "unread".to_owned()),
+        ];
+        counts.extend(config.status_counts.iter().map(|status_count| {
+            (
+                count_matching(&|msg| status_count.query.matches(msg)),
+                status_count.name.clone(),
             )
-        })?)?
+        }));
+
+        let summary = counts
+            .into_iter()
+            .filter(|(count, _)| *count > 0)
+            .map(|(count, name)| format!("{count} {name}"))
+            .collect::<Vec<_>>();
+        if !summary.is_empty() {
+            println!("{}", summary.join(", "));
+        }
+    }
+
+    Ok(if new_count > 0 {
+        ExitCode::SUCCESS
     } else {
-        highlight::HighlightConfig::default()
+        ExitCode::FAILURE
     })
 }
 
-// -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -
+// Print each message as it is appended to the mailbox, like `tail -f`.  This never returns unless
+// there's an error.
+fn watch(args: &[String]) -> anyhow::Result<ExitCode> {
+    let mut full = false;
+    let mut exec = None;
+    let mut query_args = Vec::new();
 
-fn write_mbox(
-    mbox: &mbox::Mbox,
-    mbox_original_mtime: std::time::SystemTime,
-) -> anyhow::Result<i64> {
-    // Create a replacement mbox file with remaining messages.
-    let mut num_deleted_messages = 0;
-    {
-        let temp_mbox_file_path = mktemp::Temp::new_file()?;
-        {
-            let mut temp_mbox_file = fs::OpenOptions::new()
-                .write(true)
-                .open(&temp_mbox_file_path)?;
-
-            // Make sure the permissions are rw------- even though that seems to be the default.
-            temp_mbox_file.metadata()?.permissions().set_mode(0o600);
-
-            // Write the messages we're keeping.
-            for msg in mbox.iter() {
-                if !msg.has_status(mbox::Status::Deleted) {
-                    for line in msg.all_lines().iter() {
-                        writeln!(temp_mbox_file, "{line}")?;
+    let mut args_iter = args.iter();
+    while let Some(arg) = args_iter.next() {
+        match arg.as_str() {
+            "-f" | "--full" => full = true,
+            "-e" | "--exec" => {
+                exec = Some(
+                    args_iter
+                        .next()
+                        .with_context(|| format!("Missing command after '{arg}'."))?,
+                )
+            }
+            _ => query_args.push(arg.as_str()),
+        }
+    }
+
+    let query = query::Query::parse(&query_args.join(" "))?;
+    let config = config::load()?;
+
+    let mbox_path = mbox::get_mbox_path()?;
+    let mut watcher = watch::MboxWatcher::new(&mbox_path, fs::metadata(&mbox_path)?.len());
+    let mut hook_lock = hook::HookLock::new(&mbox_path);
+    hook_lock.acquire();
+
+    loop {
+        match watcher.check()? {
+            Some(watch::MboxChange::Appended(lines, _)) => {
+                let run_hooks = hook_lock.acquire();
+                for msg in mbox::Mbox::from_iter(lines).iter() {
+                    if run_hooks {
+                        if let Err(err) = hook::run_matching(&config.hooks, msg, false) {
+                            eprintln!("{err}");
+                        }
+                    }
+
+                    if !query.matches(msg) {
+                        continue;
+                    }
+
+                    if full {
+                        print_message(msg, &config);
+                    } else {
+                        println!("{}", message_summary(msg));
+                    }
+
+                    if let Some(command) = exec {
+                        if let Err(err) = hook::run(command, msg, false) {
+                            eprintln!("{err}");
+                        }
                     }
-                } else {
-                    num_deleted_messages += 1;
                 }
             }
+            Some(watch::MboxChange::Rewritten) => {
+                // Most likely another client has removed some messages.  Start again from the end.
+                watcher.reset(fs::metadata(&mbox_path)?.len());
+            }
+            None => (),
         }
 
-        let mbox_path = mbox::get_mbox_path()?;
-        if fs::metadata(&mbox_path)?.modified()? != mbox_original_mtime {
-            anyhow::bail!("Mailbox '{mbox_path}' has been updated in the background!");
-        } else {
-            fs::copy(temp_mbox_file_path, mbox_path)?;
+        std::thread::sleep(watcher.interval());
+    }
+}
+
+fn print_message(msg: &mbox::Message, config: &config::Config) {
+    print_headers(msg, config);
+
+    let mut highlighter = config.highlights.highlighter(msg);
+    for line in msg.body_lines().unwrap_or_default() {
+        println!(
+            "{}",
+            highlight::ansi_line(line, &highlighter.next_highlights(line))
+        );
+    }
+}
+
+// Print the 'From ' line and headers, with the header values highlighted, and the blank line after.
+fn print_headers(msg: &mbox::Message, config: &config::Config) {
+    let body_lines = msg.body_lines().unwrap_or_default();
+    for line in &msg.all_lines()[..msg.all_lines().len() - body_lines.len()] {
+        match line.split_once(": ") {
+            Some((header, value)) if !line.starts_with(char::is_whitespace) => println!(
+                "{header}: {}",
+                highlight::ansi_line(
+                    value,
+                    &highlight::header_highlights(&config.header_highlights, msg, header, value)
+                )
+            ),
+            _ => println!("{line}"),
+        }
+    }
+}
+
+fn message_summary(msg: &mbox::Message) -> String {
+    format!(
+        "{}  {}  {}  {}",
+        msg.status_summary(),
+        msg.field_value(mbox::FieldType::Date).unwrap_or("???"),
+        msg.field_value(mbox::FieldType::From).unwrap_or("???"),
+        msg.field_value(mbox::FieldType::Subject).unwrap_or("???"),
+    )
+}
+
+// -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -
+
+// Highlight some text to help with writing the highlight rules.  Each body line is numbered and
+// followed by what happened while highlighting it.
+fn highlight_test(args: &[String]) -> anyhow::Result<ExitCode> {
+    let (config, input) = match args {
+        [input] => (config::load()?, input),
+        [rules_path, input] => (config::load_from(std::path::Path::new(rules_path))?, input),
+        _ => anyhow::bail!("Expected [RULES] INPUT for highlight-test.\n\n{USAGE}"),
+    };
+
+    let text = if input == "-" {
+        Some(io::read_to_string(io::stdin()).context("Failed to read from stdin.")?)
+    } else if fs::exists(input)? {
+        Some(fs::read_to_string(input).with_context(|| format!("Failed to read from {input}."))?)
+    } else {
+        None
+    };
+
+    match text {
+        Some(text) => {
+            let lines = text.lines().map(str::to_owned).collect::<Vec<_>>();
+            if lines.first().is_some_and(|line| line.starts_with("From ")) {
+                for (idx, msg) in mbox::Mbox::from_iter(lines).iter().enumerate() {
+                    if idx > 0 {
+                        println!();
+                    }
+                    print_traced_message(msg, &config);
+                }
+            } else {
+                // Plain text is the body of a message without any headers.
+                print_traced_message(&mbox::Message::from_body(lines), &config);
+            }
+        }
+        None => {
+            let msg_num = input
+                .parse::<usize>()
+                .map_err(|_| anyhow::anyhow!("No such file '{input}'."))?;
+            let (lines, _, _) = mbox::read_mbox_lines(0)?;
+            let messages = mbox::Mbox::from_iter(lines);
+            let msg = msg_num
+                .checked_sub(1)
+                .and_then(|idx| messages.msg_at(idx))
+                .with_context(|| format!("No message {msg_num} in the mailbox."))?;
+            print_traced_message(msg, &config);
         }
     }
 
-    Ok(num_deleted_messages)
+    Ok(ExitCode::SUCCESS)
+}
+
+fn print_traced_message(msg: &mbox::Message, config: &config::Config) {
+    if !msg.header_lines().is_empty() {
+        print_headers(msg, config);
+    }
+
+    let mut highlighter = config.highlights.highlighter(msg).with_trace();
+    for (line_idx, line) in msg.body_lines().unwrap_or_default().iter().enumerate() {
+        let line_highlights = highlighter.next_highlights(line);
+        println!(
+            "{:>5}  {}",
+            line_idx + 1,
+            highlight::ansi_line(line, &line_highlights)
+        );
+        for event in highlighter.take_trace() {
+            println!("{:>5}  - {event}", "");
+        }
+    }
 }
 
 // -------------------------------------------------------------------------------------------------
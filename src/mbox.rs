@@ -1,6 +1,12 @@
 use anyhow::Context;
 use fxhash::FxHashMap;
 
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    os::unix::fs::PermissionsExt,
+};
+
 // -------------------------------------------------------------------------------------------------
 
 pub fn get_mbox_path() -> anyhow::Result<String> {
@@ -13,6 +19,113 @@ pub fn get_mbox_path() -> anyhow::Result<String> {
         })
 }
 
+// -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -
+
+// Read the lines of the mailbox from byte `offset` onwards, along with its modification time and
+// the offset of the end of what was read.  Only complete lines are returned, in case a delivery is
+// in progress.
+pub(crate) fn read_mbox_lines(
+    offset: u64,
+) -> anyhow::Result<(Vec<String>, std::time::SystemTime, u64)> {
+    let mbox_path = get_mbox_path()?;
+    let mut mbox_file = fs::File::open(&mbox_path)
+        .with_context(|| format!("Failed to open mbox file '{mbox_path}'."))?;
+    let mbox_mtime = mbox_file.metadata()?.modified()?;
+
+    mbox_file.seek(SeekFrom::Start(offset))?;
+    let mut text = String::new();
+    mbox_file.read_to_string(&mut text)?;
+    text.truncate(text.rfind('\n').map(|idx| idx + 1).unwrap_or(0));
+
+    let lines = text.lines().map(|line| line.to_owned()).collect();
+
+    Ok((lines, mbox_mtime, offset + text.len() as u64))
+}
+
+// -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -
+
+// A cheaper read of the mailbox which skips the message bodies unless they're needed, and isn't
+// bothered by invalid UTF-8 in them.  A missing mailbox is just empty, as it's often removed by the
+// MTA rather than left empty.
+pub(crate) fn scan_mbox(with_bodies: bool) -> anyhow::Result<Mbox> {
+    let mbox_path = get_mbox_path()?;
+    let mbox_file = match fs::File::open(&mbox_path) {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(std::iter::empty().collect());
+        }
+        result => result.with_context(|| format!("Failed to open mbox file '{mbox_path}'."))?,
+    };
+    let mut reader = BufReader::new(mbox_file);
+
+    let mut lines = Vec::new();
+    let mut in_body = false;
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        if reader.read_until(b'\n', &mut buf)? == 0 {
+            break;
+        }
+
+        let is_from_line = buf.starts_with(b"From ");
+        if is_from_line {
+            in_body = false;
+        } else if in_body && !with_bodies {
+            continue;
+        }
+
+        let line = String::from_utf8_lossy(&buf)
+            .trim_end_matches(['\n', '\r'])
+            .to_owned();
+        if !is_from_line && line.is_empty() {
+            in_body = true;
+        }
+        lines.push(line);
+    }
+
+    Ok(lines.into_iter().collect())
+}
+
+// -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -  -
+
+pub(crate) fn write_mbox(
+    mbox: &Mbox,
+    mbox_original_mtime: std::time::SystemTime,
+) -> anyhow::Result<i64> {
+    // Create a replacement mbox file with remaining messages.
+    let mut num_deleted_messages = 0;
+    {
+        let temp_mbox_file_path = mktemp::Temp::new_file()?;
+        {
+            let mut temp_mbox_file = fs::OpenOptions::new()
+                .write(true)
+                .open(&temp_mbox_file_path)?;
+
+            // Make sure the permissions are rw------- even though that seems to be the default.
+            temp_mbox_file.metadata()?.permissions().set_mode(0o600);
+
+            // Write the messages we're keeping.
+            for msg in mbox.iter() {
+                if !msg.has_status(Status::Deleted) {
+                    for line in msg.all_lines().iter() {
+                        writeln!(temp_mbox_file, "{line}")?;
+                    }
+                } else {
+                    num_deleted_messages += 1;
+                }
+            }
+        }
+
+        let mbox_path = get_mbox_path()?;
+        if fs::metadata(&mbox_path)?.modified()? != mbox_original_mtime {
+            anyhow::bail!("Mailbox '{mbox_path}' has been updated in the background!");
+        } else {
+            fs::copy(temp_mbox_file_path, mbox_path)?;
+        }
+    }
+
+    Ok(num_deleted_messages)
+}
+
 // -------------------------------------------------------------------------------------------------
 
 #[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
@@ -21,22 +134,54 @@ pub enum FieldType {
     From,
     Subject,
     Status,
+    XStatus,
     Body, // Not a field, but... kinda.
 }
 
+// The standard flags are split between the 'Status' and 'X-Status' fields, as used by mutt and
+// others.
 #[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
 pub enum Status {
     Read,
     NonRecent,
     Deleted,
+    Flagged,
+    Answered,
+    Draft,
 }
 
 impl Status {
+    pub(crate) const ALL: [Status; 6] = [
+        Status::Read,
+        Status::NonRecent,
+        Status::Deleted,
+        Status::Flagged,
+        Status::Answered,
+        Status::Draft,
+    ];
+
     pub(crate) fn field_char(&self) -> char {
         match self {
             Status::Read => 'R',
             Status::NonRecent => 'O',
             Status::Deleted => 'D',
+            Status::Flagged => 'F',
+            Status::Answered => 'A',
+            Status::Draft => 'T',
+        }
+    }
+
+    fn field_type(&self) -> FieldType {
+        match self {
+            Status::Read | Status::NonRecent | Status::Deleted => FieldType::Status,
+            Status::Flagged | Status::Answered | Status::Draft => FieldType::XStatus,
+        }
+    }
+
+    fn field_name(&self) -> &'static str {
+        match self.field_type() {
+            FieldType::XStatus => "X-Status",
+            _ => "Status",
         }
     }
 }
@@ -52,20 +197,51 @@ impl Message {
         Message { lines, field_idcs }
     }
 
+    // A message without any headers, for treating plain text as a message body.
+    pub(crate) fn from_body(body_lines: Vec<String>) -> Self {
+        let mut lines = vec!["From -".to_owned(), String::new()];
+        lines.extend(body_lines);
+
+        let mut field_idcs = FxHashMap::default();
+        field_idcs.insert(FieldType::Body, 2);
+
+        Message::new(lines, field_idcs)
+    }
+
     pub(crate) fn field(&self, field: FieldType) -> Option<&str> {
         self.field_idcs
             .get(&field)
             .map(|idx| self.lines[*idx].as_str())
     }
 
+    /// The value of a field, without its 'Name: ' prefix.
+    pub(crate) fn field_value(&self, field: FieldType) -> Option<&str> {
+        self.field(field).map(|line| {
+            line.split_once(": ")
+                .map(|(_, value)| value)
+                .unwrap_or(line)
+        })
+    }
+
+    /// The value of the first header with `name`, which is compared case insensitively.
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.header_lines().iter().find_map(|line| {
+            line.split_once(':').and_then(|(hdr_name, value)| {
+                hdr_name
+                    .eq_ignore_ascii_case(name)
+                    .then_some(value.trim_start())
+            })
+        })
+    }
+
     pub(crate) fn has_status(&self, status: Status) -> bool {
-        self.field(FieldType::Status)
-            .map(|line| line.contains(status.field_char()))
+        self.field_value(status.field_type())
+            .map(|value| value.contains(status.field_char()))
             .unwrap_or(false)
     }
 
     pub(crate) fn set_status(&mut self, status: Status) {
-        match self.field_idcs.get(&FieldType::Status) {
+        match self.field_idcs.get(&status.field_type()) {
             Some(idx) => {
                 // Append the status char if it isn't already there.
                 let line = &mut self.lines[*idx];
@@ -76,23 +252,58 @@ impl Message {
             None => {
                 // Create a new status field line and insert it.  We put it at the end of the
                 // headers, right before the blank line before the body.
-                if let Some(body_idx) = self.field_idcs.get_mut(&FieldType::Body) {
+                if let Some(body_idx) = self.field_idcs.get(&FieldType::Body) {
                     let status_idx = *body_idx - 1;
-                    *body_idx += 1;
-                    self.lines
-                        .insert(status_idx, format!("Status: {}", status.field_char()));
-                    self.field_idcs.insert(FieldType::Status, status_idx);
+                    for idx in self.field_idcs.values_mut() {
+                        if *idx >= status_idx {
+                            *idx += 1;
+                        }
+                    }
+                    self.lines.insert(
+                        status_idx,
+                        format!("{}: {}", status.field_name(), status.field_char()),
+                    );
+                    self.field_idcs.insert(status.field_type(), status_idx);
                 }
             }
         }
     }
 
-    #[allow(dead_code)]
+    /// A short summary of the status for display, e.g., 'DN!' for a new deleted flagged message.
+    pub(crate) fn status_summary(&self) -> String {
+        let del_status = if self.has_status(Status::Deleted) {
+            'D'
+        } else {
+            ' '
+        };
+
+        let read_status = if self.has_status(Status::Read) {
+            ' '
+        } else if self.has_status(Status::NonRecent) {
+            'U'
+        } else {
+            'N'
+        };
+
+        let flag_status = if self.has_status(Status::Flagged) {
+            '!'
+        } else if self.has_status(Status::Answered) {
+            'r'
+        } else {
+            ' '
+        };
+
+        format!("{del_status}{read_status}{flag_status}")
+    }
+
     pub(crate) fn unset_status(&mut self, status: Status) {
-        if let Some(idx) = self.field_idcs.get(&FieldType::Status) {
-            // The status field has a 'Status: ' prefix, but thankfully none of the field chars (R,
-            // O, D) are in it, so we can filter the entire line.
-            self.lines[*idx].retain(|ch| ch != status.field_char());
+        if let Some(idx) = self.field_idcs.get(&status.field_type()) {
+            // Only filter the value, after the 'Status: ' or 'X-Status: ' prefix.
+            let line = &mut self.lines[*idx];
+            let prefix_len = line.find(": ").map(|idx| idx + 2).unwrap_or(0);
+            let value = line[prefix_len..].replace(status.field_char(), "");
+            line.truncate(prefix_len);
+            line.push_str(&value);
         }
     }
 
@@ -100,6 +311,16 @@ impl Message {
         &self.lines
     }
 
+    pub(crate) fn header_lines(&self) -> &[String] {
+        // Skip the 'From ' line and stop at the blank line before the body.
+        let end_idx = self
+            .field_idcs
+            .get(&FieldType::Body)
+            .map(|body_idx| body_idx.saturating_sub(1))
+            .unwrap_or(self.lines.len());
+        &self.lines[1.min(end_idx)..end_idx]
+    }
+
     pub(crate) fn body_lines(&self) -> Option<&[String]> {
         self.field_idcs
             .get(&FieldType::Body)
@@ -164,6 +385,18 @@ impl Mbox {
     pub(crate) fn iter_mut(&mut self) -> impl Iterator<Item = &mut Message> {
         self.messages.iter_mut()
     }
+
+    /// Add the messages from `other` to the end, e.g., newly delivered mail.
+    pub(crate) fn append(&mut self, other: Mbox) {
+        self.messages.extend(other.messages);
+    }
+
+    /// Mark every message as no longer new, as is done before writing them back.
+    pub(crate) fn mark_all_seen(&mut self) {
+        for msg in self.iter_mut() {
+            msg.set_status(Status::NonRecent);
+        }
+    }
 }
 
 // -------------------------------------------------------------------------------------------------
@@ -181,6 +414,7 @@ impl FieldsParser {
                 ("From: ", FieldType::From),
                 ("Subject: ", FieldType::Subject),
                 ("Status: ", FieldType::Status),
+                ("X-Status: ", FieldType::XStatus),
             ],
         }
     }
@@ -190,13 +424,15 @@ impl FieldsParser {
             .iter()
             .enumerate()
             .fold(FxHashMap::default(), |mut fields, (idx, line)| {
-                if let Some(field) = self
+                if fields.contains_key(&FieldType::Body) {
+                    // Anything which looks like a field in the body isn't one.
+                } else if let Some(field) = self
                     .field_prefixes
                     .iter()
                     .find_map(|(prefix, field)| line.starts_with(prefix).then_some(field))
                 {
                     fields.insert(*field, idx);
-                } else if line.is_empty() && !fields.contains_key(&FieldType::Body) {
+                } else if line.is_empty() {
                     fields.insert(FieldType::Body, idx + 1);
                 }
                 fields
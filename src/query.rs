@@ -0,0 +1,866 @@
+use crate::mbox::{self, FieldType, Message};
+
+use std::iter::Peekable;
+
+// -------------------------------------------------------------------------------------------------
+// The query language used by the TUI filter prompt, the `search` command and config rules.  E.g.,
+//
+//   from:cron (subject:/fail/ or body:"disk full") not deleted date:7d..
+//
+// Terms next to each other must all match, `or` (or `|`) matches either side and `not` (or `!` or
+// `-`) inverts a term.  Parentheses group terms.  `and` binds more tightly than `or`.
+//
+// Field terms are `<field>:<pattern>` where the pattern is either a plain word, a quoted string
+// (both matched case insensitively as a substring) or a `/regex/`.  The fields are `from`, `to`,
+// `cc`, `subject`, `body`, `date` and `header.<name>` for any other header.
+//
+// A plain word `date:` pattern is instead a date or range of dates, see `DateRange`.
+//
+// Bare words are either status terms (`read`, `unread`, `new`, `old`, `deleted`, `flagged`,
+// `answered`, `draft`) or, failing that, are matched against the From and Subject fields.
+
+#[derive(Debug)]
+pub(crate) struct Query {
+    source: String,
+    expr: Expr,
+}
+
+#[derive(Debug)]
+enum Expr {
+    All,
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    Not(Box<Expr>),
+    Term(Term),
+}
+
+#[derive(Debug)]
+enum Term {
+    Field(FieldType, Pattern),
+    Header(String, Pattern),
+    Body(Pattern),
+    AnyField(Pattern),
+    Date(DateRange),
+    Read,
+    Unread,
+    New,
+    Old,
+    Deleted,
+    Flagged,
+    Answered,
+    Draft,
+}
+
+#[derive(Debug)]
+enum Pattern {
+    Text(String),
+    Regex(regex::Regex),
+}
+
+impl Query {
+    pub(crate) fn parse(query_str: &str) -> anyhow::Result<Query> {
+        let mut tokens = tokenize(query_str)?.into_iter().peekable();
+
+        let expr = if tokens.peek().is_none() {
+            Expr::All
+        } else {
+            let expr = parse_or(&mut tokens)?;
+            if let Some((col, token)) = tokens.next() {
+                anyhow::bail!("Unexpected {} at column {col} in query.", token.describe());
+            }
+            expr
+        };
+
+        Ok(Query {
+            source: query_str.to_owned(),
+            expr,
+        })
+    }
+
+    pub(crate) fn matches(&self, msg: &Message) -> bool {
+        self.expr.matches(msg)
+    }
+
+    /// Whether matching needs the message bodies, rather than just the headers.
+    pub(crate) fn uses_body(&self) -> bool {
+        self.expr.uses_body()
+    }
+}
+
+// The empty query, which matches everything.
+impl Default for Query {
+    fn default() -> Self {
+        Query {
+            source: String::new(),
+            expr: Expr::All,
+        }
+    }
+}
+
+impl std::fmt::Display for Query {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+// Queries in the config file are just strings, parsed as they're loaded.
+impl<'de> serde::Deserialize<'de> for Query {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let query_str = String::deserialize(deserializer)?;
+        Query::parse(&query_str).map_err(serde::de::Error::custom)
+    }
+}
+
+impl serde::Serialize for Query {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl Expr {
+    fn matches(&self, msg: &Message) -> bool {
+        match self {
+            Expr::All => true,
+            Expr::And(exprs) => exprs.iter().all(|expr| expr.matches(msg)),
+            Expr::Or(exprs) => exprs.iter().any(|expr| expr.matches(msg)),
+            Expr::Not(expr) => !expr.matches(msg),
+            Expr::Term(term) => term.matches(msg),
+        }
+    }
+
+    fn uses_body(&self) -> bool {
+        match self {
+            Expr::All => false,
+            Expr::And(exprs) | Expr::Or(exprs) => exprs.iter().any(|expr| expr.uses_body()),
+            Expr::Not(expr) => expr.uses_body(),
+            Expr::Term(term) => matches!(term, Term::Body(_)),
+        }
+    }
+}
+
+impl Term {
+    fn matches(&self, msg: &Message) -> bool {
+        match self {
+            Term::Field(field, pat) => msg.field_value(*field).is_some_and(|v| pat.matches(v)),
+            Term::Header(name, pat) => msg.header(name).is_some_and(|v| pat.matches(v)),
+            Term::Body(pat) => msg
+                .body_lines()
+                .map(|lines| lines.iter().any(|line| pat.matches(line)))
+                .unwrap_or(false),
+            Term::AnyField(pat) => [FieldType::From, FieldType::Subject]
+                .iter()
+                .any(|field| msg.field_value(*field).is_some_and(|v| pat.matches(v))),
+            Term::Date(range) => msg
+                .field_value(FieldType::Date)
+                .and_then(parse_message_date)
+                .is_some_and(|day| range.contains(day)),
+
+            Term::Read => msg.has_status(mbox::Status::Read),
+            Term::Unread => !msg.has_status(mbox::Status::Read),
+            Term::New => {
+                !msg.has_status(mbox::Status::Read) && !msg.has_status(mbox::Status::NonRecent)
+            }
+            Term::Old => msg.has_status(mbox::Status::NonRecent),
+            Term::Deleted => msg.has_status(mbox::Status::Deleted),
+            Term::Flagged => msg.has_status(mbox::Status::Flagged),
+            Term::Answered => msg.has_status(mbox::Status::Answered),
+            Term::Draft => msg.has_status(mbox::Status::Draft),
+        }
+    }
+}
+
+impl Pattern {
+    fn matches(&self, text: &str) -> bool {
+        match self {
+            Pattern::Text(needle) => text.to_lowercase().contains(needle),
+            Pattern::Regex(re) => re.is_match(text),
+        }
+    }
+}
+
+// -------------------------------------------------------------------------------------------------
+// Dates are compared by day, as the number of days since 1970-01-01.  The day a message was sent is
+// taken from its Date header as written, without adjusting for time zones.
+//
+// A date range is either a single date or two dates separated by `..`, either of which may be
+// omitted for an open range.  A date is `YYYY-MM-DD`, `today`, `yesterday` or `<N>d`/`<N>w` for N
+// days or weeks ago.  E.g., `date:2024-01-01..2024-01-31` or `date:7d..` for the last week.
+
+#[derive(Debug)]
+struct DateRange {
+    first: Option<i64>,
+    last: Option<i64>,
+}
+
+impl DateRange {
+    fn parse(range_str: &str, col: usize) -> anyhow::Result<DateRange> {
+        let parse_date = |date_str: &str| -> anyhow::Result<Option<i64>> {
+            if date_str.is_empty() {
+                Ok(None)
+            } else {
+                parse_date_spec(date_str).map(Some).ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "Invalid date '{date_str}' at column {col} in query; \
+                        expecting YYYY-MM-DD, today, yesterday, <N>d or <N>w."
+                    )
+                })
+            }
+        };
+
+        match range_str.split_once("..") {
+            Some((first, last)) => Ok(DateRange {
+                first: parse_date(first)?,
+                last: parse_date(last)?,
+            }),
+            None => {
+                let day = parse_date(range_str)?;
+                Ok(DateRange {
+                    first: day,
+                    last: day,
+                })
+            }
+        }
+    }
+
+    fn contains(&self, day: i64) -> bool {
+        self.first.is_none_or(|first| day >= first) && self.last.is_none_or(|last| day <= last)
+    }
+}
+
+fn parse_date_spec(date_str: &str) -> Option<i64> {
+    let today = || {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|dur| (dur.as_secs() / 86400) as i64)
+            .unwrap_or(0)
+    };
+
+    match date_str {
+        "today" => Some(today()),
+        "yesterday" => Some(today() - 1),
+        _ => {
+            // Absurdly distant dates are invalid rather than overflowing.
+            if let Some(days) = date_str.strip_suffix('d') {
+                today().checked_sub(days.parse::<i64>().ok()?)
+            } else if let Some(weeks) = date_str.strip_suffix('w') {
+                today().checked_sub(weeks.parse::<i64>().ok()?.checked_mul(7)?)
+            } else {
+                let mut parts = date_str.splitn(3, '-');
+                let year = parts.next()?.parse().ok()?;
+                let month = parts.next()?.parse().ok()?;
+                let day = parts.next()?.parse().ok()?;
+                days_from_civil(year, month, day)
+            }
+        }
+    }
+}
+
+// Parse a message date in the form 'Fri, 4 Sep 2020 11:44:49 +1000 (AEST)', where the day of the
+// week is optional.
+fn parse_message_date(date_str: &str) -> Option<i64> {
+    let date_str = date_str
+        .split_once(',')
+        .map(|(_, date)| date)
+        .unwrap_or(date_str);
+
+    let mut parts = date_str.split_whitespace();
+    let day = parts.next()?.parse().ok()?;
+    let month_str = parts.next()?;
+    let month = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ]
+    .iter()
+    .position(|name| {
+        month_str
+            .get(..3)
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case(name))
+    })?;
+    let year = parts.next()?.parse().ok()?;
+
+    days_from_civil(year, month as u32 + 1, day)
+}
+
+// Days since 1970-01-01 for a proleptic Gregorian date, using Howard Hinnant's `days_from_civil`.
+fn days_from_civil(year: i64, month: u32, day: u32) -> Option<i64> {
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let year = if month <= 2 {
+        year.checked_sub(1)?
+    } else {
+        year
+    };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month_idx = (month as i64 + 9) % 12;
+    let day_of_year = (153 * month_idx + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    // The year may be anything up to `i64::MAX`, so only the era can overflow.
+    era.checked_mul(146097)?
+        .checked_add(day_of_era)?
+        .checked_sub(719468)
+}
+
+// -------------------------------------------------------------------------------------------------
+
+#[derive(Debug)]
+enum Token {
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+    Term(Option<String>, Value),
+}
+
+#[derive(Debug)]
+enum Value {
+    Word(String),
+    Quoted(String),
+    Regex(String),
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Token::LParen => "'('".to_owned(),
+            Token::RParen => "')'".to_owned(),
+            Token::And => "'and'".to_owned(),
+            Token::Or => "'or'".to_owned(),
+            Token::Not => "'not'".to_owned(),
+            Token::Term(Some(field), _) => format!("'{field}:'"),
+            Token::Term(None, Value::Word(word)) => format!("'{word}'"),
+            Token::Term(None, Value::Quoted(text)) => format!("\"{text}\""),
+            Token::Term(None, Value::Regex(re_str)) => format!("/{re_str}/"),
+        }
+    }
+}
+
+type Tokens = Peekable<std::vec::IntoIter<(usize, Token)>>;
+
+type Chars<'a> = Peekable<std::iter::Enumerate<std::str::Chars<'a>>>;
+
+// Split the query into tokens, each paired with its 1-based column for error messages.  Term tokens
+// are a bare word, a quoted string or a regex paired with their optional field name, e.g.,
+// `subject:/fail/` becomes `Term(Some("subject"), Regex("fail"))`.
+fn tokenize(query_str: &str) -> anyhow::Result<Vec<(usize, Token)>> {
+    let mut tokens = Vec::new();
+    let mut chars: Chars = query_str.chars().enumerate().peekable();
+
+    let is_word_end = |ch: char| ch.is_whitespace() || ch == '(' || ch == ')';
+
+    let delimited = |chars: &mut Chars, start_col: usize, delim: char| {
+        let mut value = String::new();
+        while let Some((_, ch)) = chars.next() {
+            match ch {
+                '\\' if chars.peek().is_some_and(|(_, next_ch)| *next_ch == delim) => {
+                    value.push(delim);
+                    chars.next();
+                }
+                ch if ch == delim => return Ok(value),
+                ch => value.push(ch),
+            }
+        }
+        anyhow::bail!("Missing closing {delim} for the {delim} at column {start_col} in query.")
+    };
+
+    while let Some(&(idx, ch)) = chars.peek() {
+        let col = idx + 1;
+
+        if ch.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        match ch {
+            '(' | ')' | '|' | '!' => {
+                chars.next();
+                tokens.push((
+                    col,
+                    match ch {
+                        '(' => Token::LParen,
+                        ')' => Token::RParen,
+                        '|' => Token::Or,
+                        _ => Token::Not,
+                    },
+                ));
+                continue;
+            }
+            '-' => {
+                // A leading '-' negates the term or group which must directly follow it.
+                chars.next();
+                if chars
+                    .peek()
+                    .is_some_and(|(_, ch)| !ch.is_whitespace() && *ch != ')')
+                {
+                    tokens.push((col, Token::Not));
+                    continue;
+                }
+                anyhow::bail!("Expecting a term after '-' at column {col} in query.");
+            }
+            _ => (),
+        }
+
+        // Gather a word, stopping after a field separator.
+        let mut word = String::new();
+        while let Some(&(_, ch)) = chars.peek() {
+            if is_word_end(ch) || ((ch == '"' || ch == '/') && word.is_empty()) {
+                break;
+            }
+            word.push(ch);
+            chars.next();
+            if ch == ':' {
+                break;
+            }
+        }
+
+        let (field, word) = match word.strip_suffix(':') {
+            Some(field) => (Some(field.to_lowercase()), String::new()),
+            None => (None, word),
+        };
+
+        let value = if !word.is_empty() {
+            Value::Word(word)
+        } else {
+            match chars.peek().copied() {
+                Some((idx, '"')) => {
+                    chars.next();
+                    Value::Quoted(delimited(&mut chars, idx + 1, '"')?)
+                }
+                Some((idx, '/')) => {
+                    chars.next();
+                    Value::Regex(delimited(&mut chars, idx + 1, '/')?)
+                }
+                _ => {
+                    // A field followed by a plain word.
+                    let mut value = String::new();
+                    while let Some(&(_, ch)) = chars.peek() {
+                        if is_word_end(ch) {
+                            break;
+                        }
+                        value.push(ch);
+                        chars.next();
+                    }
+                    if value.is_empty() {
+                        anyhow::bail!(
+                            "Missing value for '{}:' at column {col} in query.",
+                            field.unwrap_or_default()
+                        );
+                    }
+                    Value::Word(value)
+                }
+            }
+        };
+
+        tokens.push((
+            col,
+            match (&field, &value) {
+                (None, Value::Word(word)) if word.eq_ignore_ascii_case("and") => Token::And,
+                (None, Value::Word(word)) if word.eq_ignore_ascii_case("or") => Token::Or,
+                (None, Value::Word(word)) if word.eq_ignore_ascii_case("not") => Token::Not,
+                _ => Token::Term(field, value),
+            },
+        ));
+    }
+
+    Ok(tokens)
+}
+
+// or    := and ('or' and)*
+// and   := unary ('and'? unary)*
+// unary := 'not' unary | '(' or ')' | term
+
+fn parse_or(tokens: &mut Tokens) -> anyhow::Result<Expr> {
+    let mut exprs = vec![parse_and(tokens)?];
+    while let Some((col, _)) = tokens.next_if(|(_, token)| matches!(token, Token::Or)) {
+        expect_term_after(tokens, col, "or")?;
+        exprs.push(parse_and(tokens)?);
+    }
+
+    Ok(if exprs.len() == 1 {
+        exprs.pop().unwrap()
+    } else {
+        Expr::Or(exprs)
+    })
+}
+
+fn parse_and(tokens: &mut Tokens) -> anyhow::Result<Expr> {
+    let mut exprs = vec![parse_unary(tokens)?];
+    loop {
+        match tokens.peek() {
+            Some(&(col, Token::And)) => {
+                tokens.next();
+                expect_term_after(tokens, col, "and")?;
+                exprs.push(parse_unary(tokens)?);
+            }
+            Some((_, Token::LParen | Token::Not | Token::Term(..))) => {
+                exprs.push(parse_unary(tokens)?);
+            }
+            _ => break,
+        }
+    }
+
+    Ok(if exprs.len() == 1 {
+        exprs.pop().unwrap()
+    } else {
+        Expr::And(exprs)
+    })
+}
+
+fn parse_unary(tokens: &mut Tokens) -> anyhow::Result<Expr> {
+    match tokens.next() {
+        Some((col, Token::Not)) => {
+            expect_term_after(tokens, col, "not")?;
+            Ok(Expr::Not(Box::new(parse_unary(tokens)?)))
+        }
+        Some((col, Token::LParen)) => {
+            let expr = parse_or(tokens)?;
+            match tokens.next() {
+                Some((_, Token::RParen)) => Ok(expr),
+                Some((bad_col, token)) => anyhow::bail!(
+                    "Expecting ')' to close the '(' at column {col} but found {} at column \
+                    {bad_col} in query.",
+                    token.describe()
+                ),
+                None => anyhow::bail!("Missing ')' to close the '(' at column {col} in query."),
+            }
+        }
+        Some((col, Token::Term(field, value))) => parse_term(col, field, value).map(Expr::Term),
+        Some((col, token)) => anyhow::bail!(
+            "Expecting a term but found {} at column {col} in query.",
+            token.describe()
+        ),
+        None => anyhow::bail!("Unexpected end of query; expecting a term."),
+    }
+}
+
+// Report an operator at the end of the query with its column, rather than just running out.
+fn expect_term_after(tokens: &mut Tokens, col: usize, operator: &str) -> anyhow::Result<()> {
+    if tokens.peek().is_none() {
+        anyhow::bail!("Expecting a term after '{operator}' at column {col} in query.");
+    }
+    Ok(())
+}
+
+fn parse_term(col: usize, field: Option<String>, value: Value) -> anyhow::Result<Term> {
+    let pattern = |value: Value| -> anyhow::Result<Pattern> {
+        Ok(match value {
+            Value::Word(text) | Value::Quoted(text) => Pattern::Text(text.to_lowercase()),
+            Value::Regex(re_str) => Pattern::Regex(regex::Regex::new(&re_str).map_err(|err| {
+                anyhow::anyhow!("Invalid regex /{re_str}/ at column {col} in query: {err}")
+            })?),
+        })
+    };
+
+    match field.as_deref() {
+        None => Ok(match value {
+            Value::Word(word) => match word.to_lowercase().as_str() {
+                "read" => Term::Read,
+                "unread" => Term::Unread,
+                "new" => Term::New,
+                "old" => Term::Old,
+                "deleted" => Term::Deleted,
+                "flagged" => Term::Flagged,
+                "answered" => Term::Answered,
+                "draft" => Term::Draft,
+                _ => Term::AnyField(pattern(Value::Word(word))?),
+            },
+            value => Term::AnyField(pattern(value)?),
+        }),
+
+        Some("from") => Ok(Term::Field(FieldType::From, pattern(value)?)),
+        Some("subject") => Ok(Term::Field(FieldType::Subject, pattern(value)?)),
+        Some("body") => Ok(Term::Body(pattern(value)?)),
+        Some(name @ ("to" | "cc")) => Ok(Term::Header(name.to_owned(), pattern(value)?)),
+        Some("date") => Ok(match value {
+            Value::Word(range_str) => Term::Date(DateRange::parse(&range_str, col)?),
+            value => Term::Field(FieldType::Date, pattern(value)?),
+        }),
+
+        Some(field_str) => match field_str.strip_prefix("header.") {
+            Some(name) if !name.is_empty() => Ok(Term::Header(name.to_owned(), pattern(value)?)),
+            _ => Err(anyhow::anyhow!(
+                "Unknown field '{field_str}:' at column {col} in query; expecting from, to, cc, \
+                subject, body, date or header.<name>."
+            )),
+        },
+    }
+}
+
+// -------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::iter::FromIterator;
+
+    fn mbox_from(lines: &[&str]) -> mbox::Mbox {
+        mbox::Mbox::from_iter(lines.iter().map(|line| line.to_string()))
+    }
+
+    // A message from 'Cron Daemon <root@host>' with the subject 'Cron <root@host> backup: FAILED',
+    // sent on 2020-09-04.
+    fn cron_mbox() -> mbox::Mbox {
+        mbox_from(&[
+            "From root@host Fri Sep  4 11:44:49 2020",
+            "Date: Fri, 4 Sep 2020 11:44:49 +1000 (AEST)",
+            "From: Cron Daemon <root@host>",
+            "To: bob@host",
+            "Subject: Cron <root@host> backup: FAILED",
+            "X-Cron-Env: <SHELL=/bin/sh>",
+            "Status: RO",
+            "",
+            "Backup job failed: disk full",
+        ])
+    }
+
+    fn matches(query_str: &str, mbox: &mbox::Mbox) -> bool {
+        Query::parse(query_str)
+            .unwrap_or_else(|err| panic!("Failed to parse '{}': {}", query_str, err))
+            .matches(mbox.msg_at(0).expect("The test mailbox has a message."))
+    }
+
+    fn parse_err(query_str: &str) -> String {
+        Query::parse(query_str)
+            .err()
+            .unwrap_or_else(|| panic!("Expected '{}' to fail to parse.", query_str))
+            .to_string()
+    }
+
+    #[test]
+    fn and_binds_more_tightly_than_or() {
+        let mbox = cron_mbox();
+
+        // 'failed or (nomatch and nomatch)' rather than '(failed or nomatch) and nomatch'.
+        assert!(matches(
+            "subject:failed or from:nomatch subject:nomatch",
+            &mbox
+        ));
+        assert!(matches(
+            "subject:failed | from:nomatch and subject:nomatch",
+            &mbox
+        ));
+        assert!(!matches(
+            "subject:nomatch or from:cron subject:nomatch",
+            &mbox
+        ));
+        assert!(matches(
+            "subject:nomatch or from:cron subject:backup",
+            &mbox
+        ));
+    }
+
+    #[test]
+    fn parentheses_group_terms() {
+        let mbox = cron_mbox();
+
+        assert!(!matches(
+            "(subject:failed or from:nomatch) subject:nomatch",
+            &mbox
+        ));
+        assert!(matches(
+            "(subject:nomatch or from:cron) subject:backup",
+            &mbox
+        ));
+        assert!(matches("((subject:failed))", &mbox));
+    }
+
+    #[test]
+    fn negation() {
+        let mbox = cron_mbox();
+
+        for query_str in ["not subject:failed", "!subject:failed", "-subject:failed"] {
+            assert!(!matches(query_str, &mbox), "{}", query_str);
+        }
+        for query_str in [
+            "not subject:nomatch",
+            "!subject:nomatch",
+            "-subject:nomatch",
+        ] {
+            assert!(matches(query_str, &mbox), "{}", query_str);
+        }
+
+        assert!(!matches("-(subject:nomatch or from:cron)", &mbox));
+        assert!(matches("!(subject:nomatch or from:nomatch)", &mbox));
+        assert!(matches("not (subject:nomatch or from:nomatch)", &mbox));
+        assert!(matches("not not subject:failed", &mbox));
+    }
+
+    #[test]
+    fn field_values() {
+        let mbox = cron_mbox();
+
+        // Words and quoted strings are case insensitive substrings.
+        assert!(matches("subject:Failed", &mbox));
+        assert!(matches("subject:\"backup: failed\"", &mbox));
+        assert!(!matches("subject:\"backup failed\"", &mbox));
+        assert!(matches("body:\"DISK FULL\"", &mbox));
+
+        // Regexes are case sensitive and may be anchored.
+        assert!(matches("subject:/^Cron .*FAILED$/", &mbox));
+        assert!(!matches("subject:/failed/", &mbox));
+        assert!(matches("from:/root@\\w+/", &mbox));
+
+        // Quoted strings and regexes may contain their escaped delimiter.
+        assert!(!matches("subject:\"a \\\"quote\\\"\"", &mbox));
+        assert!(!matches("subject:/a\\/b/", &mbox));
+
+        assert!(matches("to:bob", &mbox));
+        assert!(!matches("cc:bob", &mbox));
+        assert!(matches("header.x-cron-env:shell", &mbox));
+        assert!(matches("HEADER.X-Cron-Env:/^<SHELL/", &mbox));
+        assert!(!matches("header.x-missing:shell", &mbox));
+
+        // Bare words are statuses or are matched against the From and Subject.
+        assert!(matches("backup", &mbox));
+        assert!(matches("daemon", &mbox));
+        assert!(!matches("disk", &mbox));
+        assert!(matches("read old", &mbox));
+        assert!(!matches("unread", &mbox));
+    }
+
+    #[test]
+    fn absolute_dates() {
+        let mbox = cron_mbox();
+
+        assert!(matches("date:2020-09-04", &mbox));
+        assert!(!matches("date:2020-09-05", &mbox));
+        assert!(matches("date:2020-09-01..2020-09-30", &mbox));
+        assert!(matches("date:2020-09-04..2020-09-04", &mbox));
+        assert!(matches("date:2020-09-04..", &mbox));
+        assert!(!matches("date:2020-09-05..", &mbox));
+        assert!(matches("date:..2020-09-04", &mbox));
+        assert!(!matches("date:..2020-09-03", &mbox));
+
+        // A quoted date value is matched against the header text instead.
+        assert!(matches("date:\"4 sep 2020\"", &mbox));
+    }
+
+    #[test]
+    fn relative_dates() {
+        let today = parse_date_spec("today").unwrap();
+        assert_eq!(parse_date_spec("yesterday"), Some(today - 1));
+        assert_eq!(parse_date_spec("0d"), Some(today));
+        assert_eq!(parse_date_spec("7d"), Some(today - 7));
+        assert_eq!(parse_date_spec("2w"), Some(today - 14));
+        assert_eq!(parse_date_spec("xd"), None);
+        assert_eq!(parse_date_spec("2020-13-01"), None);
+
+        let range = DateRange::parse("7d..", 1).unwrap();
+        assert_eq!((range.first, range.last), (Some(today - 7), None));
+        assert!(range.contains(today) && range.contains(today - 7) && !range.contains(today - 8));
+
+        let range = DateRange::parse("..1w", 1).unwrap();
+        assert_eq!((range.first, range.last), (None, Some(today - 7)));
+        assert!(!range.contains(today) && range.contains(today - 100));
+
+        // The message is from 2020 so it's well before a week ago.
+        let mbox = cron_mbox();
+        assert!(!matches("date:1w..", &mbox));
+        assert!(matches("date:..1w", &mbox));
+    }
+
+    #[test]
+    fn message_dates() {
+        assert_eq!(
+            parse_message_date("Fri, 4 Sep 2020 11:44:49 +1000 (AEST)"),
+            Some(18509)
+        );
+        assert_eq!(parse_message_date("4 SEPTEMBER 2020 11:44:49"), Some(18509));
+        assert_eq!(
+            parse_message_date("Thu, 29 Feb 2024 00:00:00 +0000"),
+            Some(19782)
+        );
+        assert_eq!(parse_message_date("Fri, 4 Sepx 2020"), Some(18509));
+        assert_eq!(parse_message_date("Fri, 4 Foo 2020"), None);
+        assert_eq!(parse_message_date("yesterday"), None);
+    }
+
+    #[test]
+    fn days_since_epoch() {
+        assert_eq!(days_from_civil(1970, 1, 1), Some(0));
+        assert_eq!(days_from_civil(1969, 12, 31), Some(-1));
+        assert_eq!(days_from_civil(2000, 3, 1), Some(11017));
+        assert_eq!(days_from_civil(2020, 9, 4), Some(18509));
+        assert_eq!(days_from_civil(2024, 2, 29), Some(19782));
+        assert_eq!(days_from_civil(2024, 0, 1), None);
+        assert_eq!(days_from_civil(2024, 13, 1), None);
+        assert_eq!(days_from_civil(2024, 1, 0), None);
+        assert_eq!(days_from_civil(2024, 1, 32), None);
+
+        // Far beyond any real date, but still mustn't overflow.
+        assert_eq!(days_from_civil(i64::MAX, 12, 31), None);
+        assert_eq!(days_from_civil(i64::MIN, 1, 1), None);
+    }
+
+    #[test]
+    fn error_messages() {
+        assert_eq!(
+            parse_err("from:cron (subject:a or b"),
+            "Missing ')' to close the '(' at column 11 in query."
+        );
+        assert_eq!(
+            parse_err("(a b"),
+            "Missing ')' to close the '(' at column 1 in query."
+        );
+        assert_eq!(
+            parse_err("subject:\"disk full"),
+            "Missing closing \" for the \" at column 9 in query."
+        );
+        assert_eq!(
+            parse_err("body:/abc"),
+            "Missing closing / for the / at column 6 in query."
+        );
+        assert!(parse_err("a subject:/(unclosed/")
+            .starts_with("Invalid regex /(unclosed/ at column 3 in query: "));
+        assert_eq!(
+            parse_err("a sender:bob"),
+            "Unknown field 'sender:' at column 3 in query; expecting from, to, cc, subject, \
+            body, date or header.<name>."
+        );
+        assert_eq!(
+            parse_err("a subject:"),
+            "Missing value for 'subject:' at column 3 in query."
+        );
+        assert_eq!(
+            parse_err("a subject: b"),
+            "Missing value for 'subject:' at column 3 in query."
+        );
+        assert_eq!(
+            parse_err("a or"),
+            "Expecting a term after 'or' at column 3 in query."
+        );
+        assert_eq!(
+            parse_err("a and"),
+            "Expecting a term after 'and' at column 3 in query."
+        );
+        assert_eq!(
+            parse_err("a -"),
+            "Expecting a term after '-' at column 3 in query."
+        );
+        assert_eq!(
+            parse_err("a or )"),
+            "Expecting a term but found ')' at column 6 in query."
+        );
+        assert_eq!(parse_err("a b)"), "Unexpected ')' at column 4 in query.");
+        assert!(parse_err("date:2020-99-01").starts_with("Invalid date '2020-99-01' at column 1"));
+
+        // Dates too distant to count the days to are invalid too.
+        for (range_str, date_str) in [
+            ("2000000000000000000w..", "2000000000000000000w"),
+            ("..-9223372036854775808d", "-9223372036854775808d"),
+            ("-9223372036854775807d", "-9223372036854775807d"),
+            ("9223372036854775807-01-01", "9223372036854775807-01-01"),
+        ] {
+            let query_str = format!("date:{}", range_str);
+            assert!(
+                parse_err(&query_str).starts_with(&format!("Invalid date '{}'", date_str)),
+                "'{}' should be an invalid date.",
+                query_str
+            );
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use crate::mbox;
+
+use std::{fs, time::Duration, time::SystemTime};
+
+// -------------------------------------------------------------------------------------------------
+// Watch the mailbox for newly delivered mail.  On Linux inotify tells us when the file has been
+// written, otherwise (or if inotify can't be used) we fall back to polling its size.  Deliveries
+// are only picked up once the size has stopped changing, so we don't read half a message.
+
+pub(crate) enum MboxChange {
+    // The lines of newly appended messages and the mailbox modification time after them.
+    Appended(Vec<String>, SystemTime),
+
+    // The mailbox has been changed other than by appending, e.g., by another mail client.
+    Rewritten,
+}
+
+pub(crate) struct MboxWatcher {
+    path: String,
+
+    #[cfg(target_os = "linux")]
+    inotify: Option<inotify::Inotify>,
+
+    // How much of the mailbox has been loaded, and its size when last checked.
+    loaded_len: u64,
+    last_len: u64,
+
+    // Once the mailbox has been rewritten we can't sensibly load any more of it.
+    rewritten: bool,
+}
+
+impl MboxWatcher {
+    pub(crate) fn new(path: &str, loaded_len: u64) -> MboxWatcher {
+        MboxWatcher {
+            path: path.to_owned(),
+            #[cfg(target_os = "linux")]
+            inotify: inotify::Inotify::init().ok().and_then(|inotify| {
+                inotify
+                    .watches()
+                    .add(
+                        path,
+                        inotify::WatchMask::MODIFY
+                            | inotify::WatchMask::ATTRIB
+                            | inotify::WatchMask::DELETE_SELF
+                            | inotify::WatchMask::MOVE_SELF,
+                    )
+                    .ok()
+                    .map(|_| inotify)
+            }),
+            loaded_len,
+            last_len: loaded_len,
+            rewritten: false,
+        }
+    }
+
+    /// How long to wait between checks.  With inotify a check is very cheap.
+    pub(crate) fn interval(&self) -> Duration {
+        #[cfg(target_os = "linux")]
+        if self.inotify.is_some() {
+            return Duration::from_millis(250);
+        }
+
+        Duration::from_secs(2)
+    }
+
+    /// Forget about any changes up to `loaded_len`, after the mailbox has been written and read
+    /// again by us.
+    pub(crate) fn reset(&mut self, loaded_len: u64) {
+        // Ignore the events from our own writes.
+        self.written_to();
+        self.loaded_len = loaded_len;
+        self.last_len = loaded_len;
+        self.rewritten = false;
+    }
+
+    pub(crate) fn check(&mut self) -> anyhow::Result<Option<MboxChange>> {
+        if self.rewritten || !(self.written_to() || self.last_len != self.loaded_len) {
+            return Ok(None);
+        }
+
+        let len = fs::metadata(&self.path)?.len();
+        if len < self.loaded_len {
+            self.rewritten = true;
+            return Ok(Some(MboxChange::Rewritten));
+        }
+        if len != self.last_len {
+            // Wait until the next check to see if it's still growing.
+            self.last_len = len;
+            return Ok(None);
+        }
+        if len == self.loaded_len {
+            return Ok(None);
+        }
+
+        let (lines, mtime, end_offset) = mbox::read_mbox_lines(self.loaded_len)?;
+        if lines.first().is_some_and(|line| !line.starts_with("From ")) {
+            self.rewritten = true;
+            return Ok(Some(MboxChange::Rewritten));
+        }
+
+        self.loaded_len = end_offset;
+        self.last_len = end_offset;
+        Ok(Some(MboxChange::Appended(lines, mtime)))
+    }
+
+    // Whether the mailbox may have been changed since the last check.  Without inotify we have to
+    // assume so.
+    fn written_to(&mut self) -> bool {
+        #[cfg(target_os = "linux")]
+        if let Some(inotify) = self.inotify.as_mut() {
+            let mut buffer = [0; 1024];
+            let mut changed = false;
+            let mut watch_removed = false;
+            while let Ok(events) = inotify.read_events(&mut buffer) {
+                for event in events {
+                    changed = true;
+                    watch_removed |= event.mask.intersects(
+                        inotify::EventMask::DELETE_SELF
+                            | inotify::EventMask::MOVE_SELF
+                            | inotify::EventMask::IGNORED,
+                    );
+                }
+            }
+
+            if watch_removed {
+                // The file has been replaced, so we can't watch it any more.  Poll instead.
+                self.inotify = None;
+            }
+            return changed;
+        }
+
+        true
+    }
+}
+
+// -------------------------------------------------------------------------------------------------